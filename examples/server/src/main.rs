@@ -2,8 +2,8 @@ use std::{io::Read, sync::Arc, vec};
 // use std::{fs::{self, File}, path::PathBuf};
 
 use clap::Parser;
-use log::info;
-use ratls::{RaTlsServer, ChainVerifier};
+use log::{error, info};
+use ratls::{RaTlsServer, ChainVerifier, ServeOptions};
 #[cfg(feature = "veraison")]
 use veraison_verifier::VeraisonTokenVerifer;
 #[cfg(feature = "realm")]
@@ -79,16 +79,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         server_privatekey_path: args.server_privkey
     })?;
 
-    for mut conn in server.connections(args.server_bind_address)?.flatten() {
+    let handle = server.serve(args.server_bind_address, ServeOptions::default(), |mut conn| {
         info!("New connection accepted");
+        if let Some(attestation) = conn.peer_attestation() {
+            info!("Client realm identity: {}", attestation.identity_hex());
+        }
         let mut buf = vec![0; 0x100];
 
-        while let Ok(len) = conn.stream().read(&mut buf) {
-            info!("Message from client: {:?}", String::from_utf8(buf[0..len].to_vec())?);
+        while let Ok(len @ 1..) = conn.stream().read(&mut buf) {
+            info!("Message from client: {:?}", String::from_utf8_lossy(&buf[0..len]));
         }
 
         info!("Connection closed");
-    }
+    }, |peer, err| {
+        error!("Connection from {:?} failed: {}", peer, err);
+    })?;
+
+    info!("Listening on {}", handle.local_addr());
+    handle.join();
 
     Ok(())
 }
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use crate::{async_connection::RaTlsStream, config::HANDSHAKE_MSG, error::RaTlsError};
+use crate::{builder::RaTlsClientBuilder, client::{make_server_name, ClientConfigFactory, ClientMode}};
+use crate::post_handshake::{ExporterChallenges, Side};
+use crate::failure::with_report;
+
+pub struct RaTlsConnector {
+    config_factory: ClientConfigFactory
+}
+
+impl RaTlsConnector {
+    pub fn new(mode: ClientMode) -> Result<Self, RaTlsError> {
+        Ok(Self::from_config_factory(RaTlsClientBuilder::from_mode(mode)?.build_config_factory()?))
+    }
+
+    pub fn from_config_factory(config_factory: ClientConfigFactory) -> Self {
+        Self { config_factory }
+    }
+
+    pub async fn connect<IO>(&self, server_name: String, sock: IO) -> Result<RaTlsStream<TlsStream<IO>>, RaTlsError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin
+    {
+        let (config, verifier, tokens) = self.config_factory.config_for_connection()?;
+        let server_name = make_server_name(verifier.as_deref(), server_name)?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, sock)
+            .await
+            .map_err(|err| with_report(err.into(), verifier.as_deref()))?;
+
+        let mut tlsconn = RaTlsStream::new(stream, verifier, tokens);
+        let conn = tlsconn.get_ref().get_ref().1;
+        let legacy_handshake = self.config_factory.protocol().check(conn.alpn_protocol())?;
+
+        match self.config_factory.post_handshake() {
+            Some(post_handshake) => {
+                let challenges = ExporterChallenges::from_connection(conn, Side::Client)?;
+                let tokens = tlsconn.tokens();
+                let attestation = post_handshake.exchange_async(&mut tlsconn, challenges, Side::Client, None, &tokens).await?;
+                tlsconn.set_peer_attestation(attestation);
+            },
+            None if legacy_handshake => Self::handshake(&mut tlsconn).await?,
+            None => ()
+        }
+        Ok(tlsconn)
+    }
+
+    async fn handshake<IO>(conn: &mut RaTlsStream<TlsStream<IO>>) -> Result<(), RaTlsError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin
+    {
+        let msg = HANDSHAKE_MSG;
+
+        let mut resp = vec![0; msg.len()];
+        conn.read_exact(&mut resp).await?;
+
+        conn.write_all(msg.as_bytes()).await?;
+        conn.flush().await?;
+
+        if resp.as_slice() == msg.as_bytes() {
+            Ok(())
+        } else {
+            Err(RaTlsError::HandshakeError)
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{io, pin::Pin, sync::Arc, task::{Context, Poll}};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::{attestation::PeerAttestation, cert_verifier::RaTlsCertVeryfier, error::RaTlsError};
+use crate::channel_binding::{ChannelBinding, ExchangedTokens};
+use crate::post_handshake::Side;
+
+// Async counterpart of RaTlsConnection, wraps a tokio-rustls stream.
+pub struct RaTlsStream<T> {
+    stream: T,
+    verifier: Option<Arc<RaTlsCertVeryfier>>,
+    // Set when the peer is attested after the handshake
+    attestation: Option<Arc<PeerAttestation>>,
+    tokens: Arc<ExchangedTokens>
+}
+
+impl<T> RaTlsStream<T> {
+    pub(crate) fn new(stream: T, verifier: Option<Arc<RaTlsCertVeryfier>>, tokens: Arc<ExchangedTokens>) -> Self {
+        Self { stream, verifier, attestation: None, tokens }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+
+    // None if the peer was not attested.
+    pub fn peer_attestation(&self) -> Option<Arc<PeerAttestation>> {
+        self.attestation
+            .clone()
+            .or_else(|| self.verifier.as_ref().and_then(|verifier| verifier.peer_attestation()))
+    }
+
+    pub(crate) fn set_peer_attestation(&mut self, attestation: Option<Arc<PeerAttestation>>) {
+        self.attestation = attestation;
+    }
+
+    pub(crate) fn tokens(&self) -> Arc<ExchangedTokens> {
+        self.tokens.clone()
+    }
+}
+
+impl<IO> RaTlsStream<tokio_rustls::server::TlsStream<IO>> {
+    pub fn channel_binding(&self) -> Result<ChannelBinding, RaTlsError> {
+        ChannelBinding::from_connection(self.stream.get_ref().1, Side::Server, &self.tokens, self.peer_attestation().as_deref())
+    }
+}
+
+impl<IO> RaTlsStream<tokio_rustls::client::TlsStream<IO>> {
+    pub fn channel_binding(&self) -> Result<ChannelBinding, RaTlsError> {
+        ChannelBinding::from_connection(self.stream.get_ref().1, Side::Client, &self.tokens, self.peer_attestation().as_deref())
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for RaTlsStream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for RaTlsStream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,97 @@
+use std::{net::IpAddr, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use rustls::server::Acceptor;
+use tokio_rustls::{server::TlsStream, LazyConfigAcceptor};
+use crate::{async_connection::RaTlsStream, config::HANDSHAKE_MSG, error::RaTlsError};
+use crate::{builder::RaTlsServerBuilder, server::{ServerConfigFactory, ServerMode}};
+use crate::post_handshake::{ExporterChallenges, Side};
+use crate::failure::with_report;
+
+// The certificate is created on the blocking thread pool once the
+// ClientHello is read, so the token resolver and the rate limiter don't hold
+// up the runtime workers. Token batching is refused, every handshake of a
+// batch would hold a blocking thread waiting for the shared token.
+pub struct RaTlsAcceptor {
+    config_factory: Arc<ServerConfigFactory>
+}
+
+impl RaTlsAcceptor {
+    pub fn new(mode: ServerMode) -> Result<Self, RaTlsError> {
+        Self::from_config_factory(RaTlsServerBuilder::from_mode(mode)?.build_config_factory()?)
+    }
+
+    pub fn from_config_factory(config_factory: ServerConfigFactory) -> Result<Self, RaTlsError> {
+        if config_factory.batches_tokens() {
+            return Err(RaTlsError::TokenBatchingUnsupported);
+        }
+        Ok(Self { config_factory: Arc::new(config_factory) })
+    }
+
+    // The token rate limit is applied with the global bucket only, see
+    // accept_from_peer.
+    pub async fn accept<IO>(&self, sock: IO) -> Result<RaTlsStream<TlsStream<IO>>, RaTlsError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin
+    {
+        self.accept_from(sock, None).await
+    }
+
+    // The peer address is used to apply the token rate limit per source.
+    pub async fn accept_from_peer<IO>(&self, sock: IO, peer: IpAddr) -> Result<RaTlsStream<TlsStream<IO>>, RaTlsError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin
+    {
+        self.accept_from(sock, Some(peer)).await
+    }
+
+    async fn accept_from<IO>(&self, sock: IO, peer: Option<IpAddr>) -> Result<RaTlsStream<TlsStream<IO>>, RaTlsError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin
+    {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), sock).await?;
+        let server_name = start.client_hello().server_name().map(str::to_owned);
+        let config_factory = self.config_factory.clone();
+        let (config, verifier, tokens) = tokio::task::spawn_blocking(move || config_factory.config_for_hello(server_name.as_deref(), peer))
+            .await
+            .map_err(std::io::Error::other)?;
+        let stream = start
+            .into_stream(Arc::new(config))
+            .await
+            .map_err(|err| with_report(err.into(), verifier.as_deref()))?;
+
+        let mut tlsconn = RaTlsStream::new(stream, verifier, tokens);
+        let conn = tlsconn.get_ref().get_ref().1;
+        let legacy_handshake = self.config_factory.protocol().check(conn.alpn_protocol())?;
+
+        match self.config_factory.post_handshake() {
+            Some(post_handshake) => {
+                let challenges = ExporterChallenges::from_connection(conn, Side::Server)?;
+                let tokens = tlsconn.tokens();
+                let attestation = post_handshake.exchange_async(&mut tlsconn, challenges, Side::Server, peer, &tokens).await?;
+                tlsconn.set_peer_attestation(attestation);
+            },
+            None if legacy_handshake => Self::handshake(&mut tlsconn).await?,
+            None => ()
+        }
+        Ok(tlsconn)
+    }
+
+    async fn handshake<IO>(conn: &mut RaTlsStream<TlsStream<IO>>) -> Result<(), RaTlsError>
+    where
+        IO: AsyncRead + AsyncWrite + Unpin
+    {
+        let msg = HANDSHAKE_MSG;
+
+        conn.write_all(msg.as_bytes()).await?;
+        conn.flush().await?;
+
+        let mut resp = vec![0; msg.len()];
+        conn.read_exact(&mut resp).await?;
+
+        if resp.as_slice() == msg.as_bytes() {
+            Ok(())
+        } else {
+            Err(RaTlsError::HandshakeError)
+        }
+    }
+}
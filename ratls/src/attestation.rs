@@ -0,0 +1,73 @@
+use rust_rsi::{PlatformClaims, RealmClaims};
+use crate::evidence::{EvidenceClaims, VerifiedEvidence};
+use crate::identity::KeyOrigin;
+
+// Result of a successful verification of the peer's attestation token,
+// available once the TLS handshake has completed.
+#[derive(Debug)]
+pub struct PeerAttestation {
+    media_type: String,
+    raw_token: Vec<u8>,
+    evidence: VerifiedEvidence,
+    key_origin: Option<KeyOrigin>
+}
+
+impl PeerAttestation {
+    pub(crate) fn new(media_type: String, raw_token: Vec<u8>, evidence: VerifiedEvidence) -> Self {
+        Self { media_type, raw_token, evidence, key_origin: None }
+    }
+
+    pub(crate) fn with_key_origin(mut self, key_origin: Option<KeyOrigin>) -> Self {
+        self.key_origin = key_origin;
+        self
+    }
+
+    pub fn media_type(&self) -> &str {
+        &self.media_type
+    }
+
+    pub fn raw_token(&self) -> &[u8] {
+        &self.raw_token
+    }
+
+    pub fn challenge(&self) -> &[u8] {
+        &self.evidence.challenge
+    }
+
+    pub fn claims(&self) -> &EvidenceClaims {
+        &self.evidence.claims
+    }
+
+    // None if the peer is not a CCA realm.
+    pub fn realm_claims(&self) -> Option<&RealmClaims> {
+        match &self.evidence.claims {
+            EvidenceClaims::Cca { realm, .. } => Some(realm),
+            _ => None
+        }
+    }
+
+    // None if the peer is not a CCA realm.
+    pub fn platform_claims(&self) -> Option<&PlatformClaims> {
+        match &self.evidence.claims {
+            EvidenceClaims::Cca { platform, .. } => Some(platform),
+            _ => None
+        }
+    }
+
+    // Stable fingerprint of the peer workload, for CCA realms it covers the
+    // RIM, RPV and the hash algorithm.
+    pub fn identity(&self) -> &[u8] {
+        &self.evidence.identity
+    }
+
+    // Where the peer's certificate key comes from, None if the peer did not
+    // say or the evidence was not sent in the certificate. The peer asserts
+    // it without the evidence vouching for it, so it is not a security claim.
+    pub fn key_origin(&self) -> Option<KeyOrigin> {
+        self.key_origin
+    }
+
+    pub fn identity_hex(&self) -> String {
+        self.evidence.identity.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
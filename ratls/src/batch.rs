@@ -0,0 +1,365 @@
+use std::{sync::{Arc, Condvar, Mutex}, time::{Duration, Instant}};
+use ciborium::Value;
+use log::{debug, error};
+use sha2::{Digest, Sha256};
+use crate::error::RaTlsError;
+
+// Proofs for bigger batches are refused when decoding
+const MAX_LEAF_COUNT: u64 = 1 << 16;
+
+// Groups the challenges arriving within the window so a single token covers
+// all of them. The token is bound to the root of a Merkle tree over the
+// challenges and every certificate carries the proof for its own one.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBatching {
+    window: Duration,
+    max_size: usize,
+    resolve_timeout: Duration
+}
+
+impl Default for TokenBatching {
+    fn default() -> Self {
+        Self { window: Duration::from_millis(20), max_size: 64, resolve_timeout: Duration::from_secs(10) }
+    }
+}
+
+impl TokenBatching {
+    // How long the first challenge of a batch waits for others
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    // The batch is closed early once it holds that many challenges
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size.clamp(1, MAX_LEAF_COUNT as usize);
+        self
+    }
+
+    // How long the rest of the batch waits for the token after the window,
+    // they fail with BatchFailed once it is over
+    pub fn with_resolve_timeout(mut self, resolve_timeout: Duration) -> Self {
+        self.resolve_timeout = resolve_timeout;
+        self
+    }
+}
+
+// RFC 6962 style hashes, leaves and nodes are domain separated
+fn leaf_hash(challenge: &[u8]) -> [u8; 32] {
+    Sha256::new().chain_update([0u8]).chain_update(challenge).finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new().chain_update([1u8]).chain_update(left).chain_update(right).finalize().into()
+}
+
+// A node without a sibling is promoted to the next level unchanged.
+#[derive(Debug)]
+pub(crate) struct MerkleTree {
+    levels: Vec<Vec<[u8; 32]>>
+}
+
+impl MerkleTree {
+    pub(crate) fn new(challenges: &[Vec<u8>]) -> Self {
+        let mut levels = vec![challenges.iter().map(|challenge| leaf_hash(challenge)).collect::<Vec<_>>()];
+
+        while levels[levels.len() - 1].len() > 1 {
+            let level = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!()
+                })
+                .collect();
+            levels.push(level);
+        }
+
+        Self { levels }
+    }
+
+    pub(crate) fn root(&self) -> [u8; 32] {
+        self.levels[self.levels.len() - 1][0]
+    }
+
+    pub(crate) fn proof(&self, index: usize) -> InclusionProof {
+        let mut path = Vec::new();
+        let mut position = index;
+
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                path.push(*sibling);
+            }
+            position /= 2;
+        }
+
+        InclusionProof { index: index as u64, leaf_count: self.levels[0].len() as u64, path }
+    }
+}
+
+// Encoded in the certificate as a CBOR array [index, leaf count, [siblings]]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct InclusionProof {
+    index: u64,
+    leaf_count: u64,
+    path: Vec<[u8; 32]>
+}
+
+impl InclusionProof {
+    // Root of the tree the challenge belongs to according to the proof
+    pub(crate) fn root(&self, challenge: &[u8]) -> Result<[u8; 32], RaTlsError> {
+        let mut hash = leaf_hash(challenge);
+        let mut position = self.index;
+        let mut count = self.leaf_count;
+        let mut path = self.path.iter();
+
+        while count > 1 {
+            if position ^ 1 < count {
+                let sibling = path.next().ok_or(RaTlsError::InvalidInclusionProof)?;
+                hash = match position % 2 {
+                    0 => node_hash(&hash, sibling),
+                    _ => node_hash(sibling, &hash)
+                };
+            }
+            position /= 2;
+            count = count.div_ceil(2);
+        }
+
+        match path.next() {
+            Some(_) => Err(RaTlsError::InvalidInclusionProof),
+            None => Ok(hash)
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, RaTlsError> {
+        let proof = Value::Array(vec![
+            Value::Integer(self.index.into()),
+            Value::Integer(self.leaf_count.into()),
+            Value::Array(self.path.iter().map(|hash| Value::Bytes(hash.to_vec())).collect())
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&proof, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, RaTlsError> {
+        let Value::Array(items) = ciborium::from_reader(data)? else {
+            return Err(RaTlsError::InvalidInclusionProof);
+        };
+        let [Value::Integer(index), Value::Integer(leaf_count), Value::Array(path)] = items.as_slice() else {
+            return Err(RaTlsError::InvalidInclusionProof);
+        };
+        let index = u64::try_from(*index).map_err(|_| RaTlsError::InvalidInclusionProof)?;
+        let leaf_count = u64::try_from(*leaf_count).map_err(|_| RaTlsError::InvalidInclusionProof)?;
+
+        if index >= leaf_count || leaf_count > MAX_LEAF_COUNT {
+            return Err(RaTlsError::InvalidInclusionProof);
+        }
+
+        let path = path
+            .iter()
+            .map(|hash| match hash {
+                Value::Bytes(hash) => <[u8; 32]>::try_from(hash.as_slice()).map_err(|_| RaTlsError::InvalidInclusionProof),
+                _ => Err(RaTlsError::InvalidInclusionProof)
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { index, leaf_count, path })
+    }
+}
+
+#[derive(Debug)]
+struct BatchToken<T> {
+    token: T,
+    tree: MerkleTree
+}
+
+#[derive(Debug)]
+struct BatchState<T> {
+    challenges: Vec<Vec<u8>>,
+    closed: bool,
+    // None while the token is being resolved, Some(None) if that failed
+    result: Option<Option<Arc<BatchToken<T>>>>
+}
+
+#[derive(Debug)]
+struct Batch<T> {
+    state: Mutex<BatchState<T>>,
+    changed: Condvar
+}
+
+impl<T> Default for Batch<T> {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(BatchState { challenges: Vec::new(), closed: false, result: None }),
+            changed: Condvar::new()
+        }
+    }
+}
+
+// The first challenge of a batch makes its caller the leader, it waits for
+// the window to pass, resolves the token and wakes up the rest of the batch.
+#[derive(Debug)]
+pub(crate) struct TokenBatcher<T> {
+    options: TokenBatching,
+    open: Mutex<Option<Arc<Batch<T>>>>
+}
+
+impl<T: Clone> TokenBatcher<T> {
+    pub(crate) fn new(options: TokenBatching) -> Self {
+        Self { options, open: Mutex::new(None) }
+    }
+
+    // Returns the token resolved for the root and the proof of the challenge.
+    pub(crate) fn resolve<F>(&self, challenge: &[u8], resolve_root: F) -> Result<(T, InclusionProof), RaTlsError>
+    where
+        F: FnOnce(&[u8]) -> Result<T, RaTlsError>
+    {
+        let deadline = Instant::now() + self.options.window + self.options.resolve_timeout;
+        let (batch, index, leader) = self.join(challenge)?;
+
+        if leader {
+            self.lead(&batch, resolve_root)?;
+        }
+
+        let mut state = batch.state.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        while state.result.is_none() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                error!("Timed out waiting for the token of a batch");
+                return Err(RaTlsError::BatchFailed);
+            }
+            state = batch.changed.wait_timeout(state, remaining).map_err(|_| RaTlsError::BatchFailed)?.0;
+        }
+
+        match &state.result {
+            Some(Some(batch_token)) => Ok((batch_token.token.clone(), batch_token.tree.proof(index))),
+            _ => Err(RaTlsError::BatchFailed)
+        }
+    }
+
+    fn join(&self, challenge: &[u8]) -> Result<(Arc<Batch<T>>, usize, bool), RaTlsError> {
+        let mut open = self.open.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        let (batch, leader) = match open.as_ref() {
+            Some(batch) => (batch.clone(), false),
+            None => (open.insert(Arc::new(Batch::default())).clone(), true)
+        };
+
+        let mut state = batch.state.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        state.challenges.push(challenge.to_vec());
+        let index = state.challenges.len() - 1;
+
+        if state.challenges.len() >= self.options.max_size {
+            // Nobody else can join, let the leader go on
+            *open = None;
+            state.closed = true;
+            batch.changed.notify_all();
+        }
+        drop(state);
+
+        Ok((batch, index, leader))
+    }
+
+    fn lead<F>(&self, batch: &Arc<Batch<T>>, resolve_root: F) -> Result<(), RaTlsError>
+    where
+        F: FnOnce(&[u8]) -> Result<T, RaTlsError>
+    {
+        let deadline = Instant::now() + self.options.window;
+        let mut state = batch.state.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        while !state.closed {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            state = batch.changed.wait_timeout(state, remaining).map_err(|_| RaTlsError::BatchFailed)?.0;
+        }
+        drop(state);
+
+        // Close the batch, the lock order is the same as in join
+        let mut open = self.open.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        if open.as_ref().is_some_and(|open| Arc::ptr_eq(open, batch)) {
+            *open = None;
+        }
+        let mut state = batch.state.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        state.closed = true;
+        drop(open);
+
+        // Nobody joins a closed batch, the rest of it may give up meanwhile
+        let tree = MerkleTree::new(&state.challenges);
+        debug!("Resolving a token for a batch of {} challenges", state.challenges.len());
+        drop(state);
+        let result = resolve_root(&tree.root());
+
+        let mut state = batch.state.lock().map_err(|_| RaTlsError::BatchFailed)?;
+        state.result = Some(result.as_ref().ok().map(|token| Arc::new(BatchToken { token: token.clone(), tree })));
+        batch.changed.notify_all();
+        drop(state);
+
+        result.map(|_| ()).inspect_err(|_| error!("Failed to resolve the token for a batch"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn batch_shares_one_token() {
+        let batcher = TokenBatcher::new(TokenBatching::default().with_window(Duration::from_secs(5)).with_max_size(2));
+
+        let roots = thread::scope(|scope| {
+            let resolvers = [b"first", b"other"].map(|challenge| {
+                let batcher = &batcher;
+                scope.spawn(move || {
+                    let (root, proof) = batcher.resolve(challenge, |root| Ok(root.to_vec())).unwrap();
+                    assert_eq!(proof.root(challenge).unwrap().as_slice(), root.as_slice());
+                    root
+                })
+            });
+            resolvers.map(|resolver| resolver.join().unwrap())
+        });
+        assert_eq!(roots[0], roots[1]);
+    }
+
+    #[test]
+    fn followers_give_up_on_a_slow_leader() {
+        let options = TokenBatching::default()
+            .with_window(Duration::from_millis(10))
+            .with_max_size(2)
+            .with_resolve_timeout(Duration::from_millis(50));
+        let batcher = TokenBatcher::<()>::new(options);
+
+        thread::scope(|scope| {
+            let leader = scope.spawn(|| batcher.resolve(b"leader", |_| {
+                thread::sleep(Duration::from_millis(500));
+                Ok(())
+            }));
+            thread::sleep(Duration::from_millis(5));
+
+            let started = Instant::now();
+            let follower = batcher.resolve(b"follower", |_| unreachable!());
+            assert!(matches!(follower, Err(RaTlsError::BatchFailed)));
+            assert!(started.elapsed() < Duration::from_millis(400));
+
+            assert!(leader.join().unwrap().is_ok());
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_acceptor_refuses_batching() {
+        use crate::{RaTlsAcceptor, RaTlsServerBuilder, SampleEvidenceResolver};
+
+        let token_resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let batched = RaTlsServerBuilder::new()
+            .with_attestation(token_resolver.clone())
+            .with_token_batching(TokenBatching::default())
+            .build_config_factory()
+            .unwrap();
+        assert!(matches!(RaTlsAcceptor::from_config_factory(batched), Err(RaTlsError::TokenBatchingUnsupported)));
+
+        let plain = RaTlsServerBuilder::new().with_attestation(token_resolver).build_config_factory().unwrap();
+        assert!(RaTlsAcceptor::from_config_factory(plain).is_ok());
+    }
+}
@@ -0,0 +1,540 @@
+use std::sync::Arc;
+use rustls::{crypto::ring::{default_provider, sign::any_supported_type}, pki_types::{CertificateDer, PrivateKeyDer},
+             server::{danger::ClientCertVerifier, WebPkiClientVerifier}, sign::{CertifiedKey, SigningKey, SingleCertAndKey}, RootCertStore};
+use crate::{cert_resolver::{KeyType, PlainCertResolver, RaTlsCertResolver, ResolverOptions},
+            cmw::EvidenceEncoding, error::RaTlsError};
+use crate::cert_issuer::CertificateIssuer;
+use crate::cert_profile::CertificateProfile;
+use crate::identity::{load_private_key, KeyOrigin, ResolverKey};
+use crate::config::{AttestationTransport, EvidenceExtension, RaTlsProtocol};
+use crate::evidence::EvidenceVerifiers;
+use crate::post_handshake::PostHandshake;
+use crate::rate_limit::{TokenRateLimit, TokenRateLimiter};
+use crate::batch::TokenBatching;
+use crate::key_pool::KeyPool;
+use crate::key_rotation::KeyRotationPolicy;
+use crate::client::{ClientConfigFactory, ClientCredentials, ClientMode, RaTlsClient, ServerVerification};
+use crate::server::{RaTlsServer, ServerCertificate, ServerConfigFactory, ServerMode};
+use crate::token_resolver::InternalTokenResolver;
+use crate::token_verifier::InternalTokenVerifier;
+use crate::tools::{self, load_certificates_from_pem, load_private_key_from_file, parse_certificates_from_pem,
+                   parse_private_key_from_pem, root_cert_store_from_certificates};
+
+// Either a regular certificate or an attestation token embedded into
+// a self-signed certificate. Setting one replaces the other.
+enum Credentials {
+    Certificate(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>),
+    Attestation(Arc<dyn InternalTokenResolver>)
+}
+
+impl Credentials {
+    fn from_pem(cert_pem: &str, key_pem: &str) -> Result<Self, RaTlsError> {
+        Ok(Self::Certificate(parse_certificates_from_pem(cert_pem)?, parse_private_key_from_pem(key_pem)?))
+    }
+
+    fn from_files(cert_path: &str, key_path: &str) -> Result<Self, RaTlsError> {
+        Ok(Self::Certificate(load_certificates_from_pem(cert_path)?, load_private_key_from_file(key_path)?))
+    }
+
+    // Media type of the evidence presented with these credentials
+    fn evidence(&self) -> Vec<&str> {
+        match self {
+            Self::Attestation(token_resolver) => vec![token_resolver.media_type()],
+            Self::Certificate(..) => vec![]
+        }
+    }
+}
+
+fn certified_key(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Arc<CertifiedKey>, RaTlsError> {
+    Ok(Arc::new(CertifiedKey::from_der(certs, key, &default_provider())?))
+}
+
+// With the post-handshake transport the evidence is exchanged over the
+// established connection, TLS itself only uses regular certificates.
+fn post_handshake(
+    transport: AttestationTransport,
+    credentials: &Option<Credentials>,
+    resolver_options: &ResolverOptions,
+    token_verifier: &Option<Arc<dyn InternalTokenVerifier>>,
+    evidence_verifiers: &Arc<EvidenceVerifiers>,
+    rate_limiter: &Option<Arc<TokenRateLimiter>>
+) -> Option<PostHandshake> {
+    if transport != AttestationTransport::PostHandshake {
+        return None;
+    }
+
+    let token_resolver = match credentials {
+        Some(Credentials::Attestation(token_resolver)) => Some(token_resolver.clone()),
+        _ => None
+    };
+
+    Some(PostHandshake {
+        token_resolver,
+        evidence_encoding: resolver_options.evidence_encoding,
+        token_verifier: token_verifier.clone(),
+        evidence_verifiers: evidence_verifiers.clone(),
+        rate_limiter: rate_limiter.clone()
+    })
+}
+
+#[derive(Default)]
+pub struct RaTlsServerBuilder {
+    credentials: Option<Credentials>,
+    resolver_options: ResolverOptions,
+    client_token_verifier: Option<Arc<dyn InternalTokenVerifier>>,
+    client_root_store: Option<RootCertStore>,
+    client_chain_anchors: Option<RootCertStore>,
+    evidence_verifiers: Arc<EvidenceVerifiers>,
+    transport: AttestationTransport,
+    strict_protocol: bool,
+    token_rate_limit: Option<TokenRateLimit>,
+    token_batching: Option<TokenBatching>,
+    key_rotation: Option<KeyRotationPolicy>,
+    attestation_key: Option<(Arc<dyn SigningKey>, KeyOrigin)>
+}
+
+impl RaTlsServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_mode(mode: ServerMode) -> Result<Self, RaTlsError> {
+        Ok(match mode {
+            ServerMode::AttestedClient { client_token_verifier, server_certificate_path, server_privatekey_path } => {
+                Self::new()
+                    .with_certificate_files(server_certificate_path, server_privatekey_path)?
+                    .with_client_attestation(client_token_verifier)
+            },
+            ServerMode::AttestedServer { server_token_resolver } => {
+                Self::new()
+                    .with_attestation(server_token_resolver)
+            },
+            ServerMode::MutualAttestation { client_token_verifier, server_token_resolver } => {
+                Self::new()
+                    .with_attestation(server_token_resolver)
+                    .with_client_attestation(client_token_verifier)
+            }
+        })
+    }
+
+    pub fn with_certificate(mut self, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Self {
+        self.credentials = Some(Credentials::Certificate(certs, key));
+        self
+    }
+
+    pub fn with_certificate_pem(mut self, cert_pem: impl AsRef<str>, key_pem: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        self.credentials = Some(Credentials::from_pem(cert_pem.as_ref(), key_pem.as_ref())?);
+        Ok(self)
+    }
+
+    pub fn with_certificate_files(mut self, cert_path: impl AsRef<str>, key_path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        self.credentials = Some(Credentials::from_files(cert_path.as_ref(), key_path.as_ref())?);
+        Ok(self)
+    }
+
+    // Attest the server with a token embedded into its certificate.
+    pub fn with_attestation(mut self, token_resolver: Arc<dyn InternalTokenResolver>) -> Self {
+        self.credentials = Some(Credentials::Attestation(token_resolver));
+        self
+    }
+
+    pub fn with_key_type(mut self, key_type: KeyType) -> Self {
+        self.resolver_options.key_type = key_type;
+        self
+    }
+
+    pub fn with_evidence_extension(mut self, evidence_extension: EvidenceExtension) -> Self {
+        self.resolver_options.evidence_extension = evidence_extension;
+        self
+    }
+
+    // Subject, SANs, validity and usages of the attested certificate.
+    pub fn with_certificate_profile(mut self, profile: CertificateProfile) -> Self {
+        self.resolver_options.profile = Arc::new(profile);
+        self
+    }
+
+    // Have the attested certificate signed by a CA instead of its own key.
+    pub fn with_certificate_issuer(mut self, issuer: CertificateIssuer) -> Self {
+        self.resolver_options.issuer = Some(Arc::new(issuer));
+        self
+    }
+
+    pub fn with_evidence_encoding(mut self, evidence_encoding: EvidenceEncoding) -> Self {
+        self.resolver_options.evidence_encoding = evidence_encoding;
+        self
+    }
+
+    pub fn with_attestation_transport(mut self, transport: AttestationTransport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    // Refuse peers that don't negotiate the ratls protocol with ALPN instead
+    // of falling back to the HELO messages.
+    pub fn with_strict_protocol(mut self, strict_protocol: bool) -> Self {
+        self.strict_protocol = strict_protocol;
+        self
+    }
+
+    // Accept attested clients, without it and client root certificates no
+    // client certificate is requested.
+    pub fn with_client_attestation(mut self, token_verifier: Arc<dyn InternalTokenVerifier>) -> Self {
+        self.client_token_verifier = Some(token_verifier);
+        self
+    }
+
+    // Accept clients with certificates issued by these CAs, next to the
+    // attested ones if client attestation is enabled too.
+    pub fn with_client_root_certificates(mut self, root_store: RootCertStore) -> Self {
+        self.client_root_store = Some(root_store);
+        self
+    }
+
+    // Attested clients must also present a certificate chaining to these
+    // anchors, see with_certificate_issuer.
+    pub fn with_client_chain_anchors(mut self, anchors: RootCertStore) -> Self {
+        self.client_chain_anchors = Some(anchors);
+        self
+    }
+
+    pub fn with_client_root_ca_pem(self, pem: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        let root_store = root_cert_store_from_certificates(parse_certificates_from_pem(pem.as_ref())?);
+        Ok(self.with_client_root_certificates(root_store))
+    }
+
+    pub fn with_client_root_ca_file(self, path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        let root_store = root_cert_store_from_certificates(load_certificates_from_pem(path.as_ref())?);
+        Ok(self.with_client_root_certificates(root_store))
+    }
+
+    // Evidence formats accepted from attested clients, CCA by default.
+    pub fn with_evidence_verifiers(mut self, evidence_verifiers: EvidenceVerifiers) -> Self {
+        self.evidence_verifiers = Arc::new(evidence_verifiers);
+        self
+    }
+
+    // Limits how often the attestation token is generated for connecting
+    // clients, see TokenRateLimit.
+    pub fn with_token_rate_limit(mut self, token_rate_limit: TokenRateLimit) -> Self {
+        self.token_rate_limit = Some(token_rate_limit);
+        self
+    }
+
+    // Lets concurrent handshakes share one token, see TokenBatching. Applies
+    // to the certificate extension transport only, building fails with
+    // TokenBatchingUnsupported otherwise. RaTlsAcceptor refuses it too.
+    pub fn with_token_batching(mut self, token_batching: TokenBatching) -> Self {
+        self.token_batching = Some(token_batching);
+        self
+    }
+
+    // Replaces the attestation key by age or by the number of handshakes.
+    // Building fails with KeyRotationUnsupported for a supplied key or the
+    // post-handshake transport.
+    pub fn with_key_rotation(mut self, key_rotation: KeyRotationPolicy) -> Self {
+        self.key_rotation = Some(key_rotation);
+        self
+    }
+
+    // Key of the attested certificate, by default a new one is generated at
+    // startup. A supplied key is never rotated.
+    pub fn with_attestation_key(mut self, key: PrivateKeyDer<'static>) -> Result<Self, RaTlsError> {
+        self.attestation_key = Some((any_supported_type(&key)?, KeyOrigin::Persistent));
+        Ok(self)
+    }
+
+    // PKCS#8 key in a PEM or DER file
+    pub fn with_attestation_key_file(self, path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        self.with_attestation_key(load_private_key(path)?)
+    }
+
+    // The key has to report its SubjectPublicKeyInfo through public_key(),
+    // building fails with UnsupportedSigningKey otherwise.
+    pub fn with_attestation_signing_key(mut self, signing_key: Arc<dyn SigningKey>) -> Self {
+        self.attestation_key = Some((signing_key, KeyOrigin::External));
+        self
+    }
+
+    pub fn build_config_factory(self) -> Result<ServerConfigFactory, RaTlsError> {
+        tools::install_default_crypto_provider();
+        let protocol = RaTlsProtocol::new(
+            self.transport,
+            &self.credentials.as_ref().map(Credentials::evidence).unwrap_or_default(),
+            &match self.client_token_verifier {
+                Some(_) => self.evidence_verifiers.media_types(),
+                None => vec![]
+            },
+            !self.strict_protocol
+        );
+        let rate_limiter = self.token_rate_limit.map(|limit| Arc::new(TokenRateLimiter::new(limit)));
+        let post_handshake = post_handshake(
+            self.transport,
+            &self.credentials,
+            &self.resolver_options,
+            &self.client_token_verifier,
+            &self.evidence_verifiers,
+            &rate_limiter
+        );
+        let cert_resolver = match self.credentials {
+            None => return Err(RaTlsError::MissingServerCredentials),
+            Some(Credentials::Certificate(certs, key)) => {
+                ServerCertificate::Fixed(Arc::new(SingleCertAndKey::from(certified_key(certs, key)?)))
+            },
+            Some(Credentials::Attestation(_)) if post_handshake.is_some() => {
+                if self.key_rotation.is_some() {
+                    return Err(RaTlsError::KeyRotationUnsupported);
+                }
+                if self.token_batching.is_some() {
+                    return Err(RaTlsError::TokenBatchingUnsupported);
+                }
+                let key = match self.attestation_key {
+                    Some((signing_key, origin)) => ResolverKey::with_origin(signing_key, origin)?,
+                    None => ResolverKey::generated(self.resolver_options.key_type.generate()?)?
+                };
+                ServerCertificate::Fixed(Arc::new(PlainCertResolver::new(key, self.resolver_options)?))
+            },
+            Some(Credentials::Attestation(token_resolver)) => {
+                self.resolver_options.check_encoding()?;
+                let resolver = match self.attestation_key {
+                    Some((signing_key, origin)) => RaTlsCertResolver::with_signing_key(token_resolver, signing_key, origin)?
+                        .apply_options(self.resolver_options),
+                    None => RaTlsCertResolver::with_options(token_resolver, self.resolver_options)?
+                };
+                let mut resolver = resolver.with_rate_limiter(rate_limiter);
+                if let Some(key_rotation) = self.key_rotation {
+                    resolver = resolver.with_key_rotation(key_rotation)?;
+                }
+                ServerCertificate::Attested(Arc::new(match self.token_batching {
+                    Some(token_batching) => resolver.with_token_batching(token_batching),
+                    None => resolver
+                }))
+            }
+        };
+
+        let client_cert_verifier: Option<Arc<dyn ClientCertVerifier>> = match self.client_root_store {
+            Some(root_store) => Some(WebPkiClientVerifier::builder(Arc::new(root_store)).build()?),
+            None => None
+        };
+
+        let client_token_verifier = match post_handshake {
+            Some(_) => None,
+            None => self.client_token_verifier
+        };
+
+        Ok(ServerConfigFactory::new(
+            client_token_verifier,
+            self.evidence_verifiers,
+            client_cert_verifier,
+            self.client_chain_anchors.map(Arc::new),
+            cert_resolver,
+            post_handshake,
+            protocol
+        ))
+    }
+
+    pub fn build(self) -> Result<RaTlsServer, RaTlsError> {
+        Ok(RaTlsServer::from_config_factory(self.build_config_factory()?))
+    }
+}
+
+#[derive(Default)]
+pub struct RaTlsClientBuilder {
+    credentials: Option<Credentials>,
+    resolver_options: ResolverOptions,
+    root_store: Option<RootCertStore>,
+    server_token_verifier: Option<Arc<dyn InternalTokenVerifier>>,
+    hybrid_server_verification: bool,
+    evidence_verifiers: Arc<EvidenceVerifiers>,
+    transport: AttestationTransport,
+    strict_protocol: bool,
+    key_pool_size: Option<usize>
+}
+
+impl RaTlsClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_mode(mode: ClientMode) -> Result<Self, RaTlsError> {
+        Ok(match mode {
+            ClientMode::AttestedClient { client_token_resolver, root_ca_path } => {
+                Self::new()
+                    .with_attestation(client_token_resolver)
+                    .with_root_ca_file(root_ca_path)?
+            },
+            ClientMode::AttestedServer { client_certificate_path, client_privatekey_path, server_token_verifier } => {
+                Self::new()
+                    .with_client_certificate_files(client_certificate_path, client_privatekey_path)?
+                    .with_server_attestation(server_token_verifier)
+            },
+            ClientMode::MutualAttestation { client_token_resolver, server_token_verifier } => {
+                Self::new()
+                    .with_attestation(client_token_resolver)
+                    .with_server_attestation(server_token_verifier)
+            }
+        })
+    }
+
+    pub fn with_client_certificate(mut self, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Self {
+        self.credentials = Some(Credentials::Certificate(certs, key));
+        self
+    }
+
+    pub fn with_client_certificate_pem(mut self, cert_pem: impl AsRef<str>, key_pem: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        self.credentials = Some(Credentials::from_pem(cert_pem.as_ref(), key_pem.as_ref())?);
+        Ok(self)
+    }
+
+    pub fn with_client_certificate_files(mut self, cert_path: impl AsRef<str>, key_path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        self.credentials = Some(Credentials::from_files(cert_path.as_ref(), key_path.as_ref())?);
+        Ok(self)
+    }
+
+    // Attest the client with a token embedded into its certificate.
+    pub fn with_attestation(mut self, token_resolver: Arc<dyn InternalTokenResolver>) -> Self {
+        self.credentials = Some(Credentials::Attestation(token_resolver));
+        self
+    }
+
+    pub fn with_key_type(mut self, key_type: KeyType) -> Self {
+        self.resolver_options.key_type = key_type;
+        self
+    }
+
+    // The attestation key is generated once and shared by all connections.
+    // With a pool every connection gets a fresh key, up to pool_size of them
+    // are generated ahead of time on a background thread.
+    pub fn with_key_pool(mut self, pool_size: usize) -> Self {
+        self.key_pool_size = Some(pool_size);
+        self
+    }
+
+    pub fn with_evidence_extension(mut self, evidence_extension: EvidenceExtension) -> Self {
+        self.resolver_options.evidence_extension = evidence_extension;
+        self
+    }
+
+    // Subject, SANs, validity and usages of the attested certificate.
+    pub fn with_certificate_profile(mut self, profile: CertificateProfile) -> Self {
+        self.resolver_options.profile = Arc::new(profile);
+        self
+    }
+
+    // Have the attested certificate signed by a CA instead of its own key.
+    pub fn with_certificate_issuer(mut self, issuer: CertificateIssuer) -> Self {
+        self.resolver_options.issuer = Some(Arc::new(issuer));
+        self
+    }
+
+    pub fn with_evidence_encoding(mut self, evidence_encoding: EvidenceEncoding) -> Self {
+        self.resolver_options.evidence_encoding = evidence_encoding;
+        self
+    }
+
+    pub fn with_attestation_transport(mut self, transport: AttestationTransport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    // Refuse peers that don't negotiate the ratls protocol with ALPN instead
+    // of falling back to the HELO messages.
+    pub fn with_strict_protocol(mut self, strict_protocol: bool) -> Self {
+        self.strict_protocol = strict_protocol;
+        self
+    }
+
+    pub fn with_root_certificates(mut self, root_store: RootCertStore) -> Self {
+        self.root_store = Some(root_store);
+        self
+    }
+
+    pub fn with_root_ca_pem(self, pem: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        let root_store = root_cert_store_from_certificates(parse_certificates_from_pem(pem.as_ref())?);
+        Ok(self.with_root_certificates(root_store))
+    }
+
+    pub fn with_root_ca_file(self, path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        let root_store = root_cert_store_from_certificates(load_certificates_from_pem(path.as_ref())?);
+        Ok(self.with_root_certificates(root_store))
+    }
+
+    // Require an attested server, takes precedence over the root certificates.
+    pub fn with_server_attestation(mut self, token_verifier: Arc<dyn InternalTokenVerifier>) -> Self {
+        self.server_token_verifier = Some(token_verifier);
+        self
+    }
+
+    // With server attestation, also require the server certificate to chain
+    // to the root certificates and to match the hostname.
+    pub fn with_hybrid_server_verification(mut self, hybrid: bool) -> Self {
+        self.hybrid_server_verification = hybrid;
+        self
+    }
+
+    // Evidence formats accepted from attested servers, CCA by default.
+    pub fn with_evidence_verifiers(mut self, evidence_verifiers: EvidenceVerifiers) -> Self {
+        self.evidence_verifiers = Arc::new(evidence_verifiers);
+        self
+    }
+
+    pub fn build_config_factory(self) -> Result<ClientConfigFactory, RaTlsError> {
+        tools::install_default_crypto_provider();
+        let protocol = RaTlsProtocol::new(
+            self.transport,
+            &match self.server_token_verifier {
+                Some(_) => self.evidence_verifiers.media_types(),
+                None => vec![]
+            },
+            &self.credentials.as_ref().map(Credentials::evidence).unwrap_or_default(),
+            !self.strict_protocol
+        );
+        let post_handshake = post_handshake(
+            self.transport,
+            &self.credentials,
+            &self.resolver_options,
+            &self.server_token_verifier,
+            &self.evidence_verifiers,
+            &None
+        );
+        let server_verification = match (self.server_token_verifier, self.root_store) {
+            (Some(_), None) if self.hybrid_server_verification => return Err(RaTlsError::MissingRootCertificates),
+            // The evidence is bound to the TLS exporter, a regular handshake
+            // checks the chain and the hostname.
+            (Some(_), Some(root_store)) if post_handshake.is_some() && self.hybrid_server_verification => {
+                ServerVerification::RootCertificates(Arc::new(root_store))
+            },
+            (Some(_), _) if post_handshake.is_some() => ServerVerification::PostHandshake,
+            (Some(token_verifier), Some(root_store)) if self.hybrid_server_verification => {
+                ServerVerification::Attestation(token_verifier, self.evidence_verifiers, Some(Arc::new(root_store)))
+            },
+            (Some(token_verifier), _) => ServerVerification::Attestation(token_verifier, self.evidence_verifiers, None),
+            (None, Some(root_store)) => ServerVerification::RootCertificates(Arc::new(root_store)),
+            (None, None) => return Err(RaTlsError::MissingServerVerification)
+        };
+        let client_credentials = match self.credentials {
+            None => None,
+            Some(Credentials::Certificate(certs, key)) => Some(ClientCredentials::Certificate(certified_key(certs, key)?)),
+            Some(Credentials::Attestation(_)) if post_handshake.is_some() => None,
+            Some(Credentials::Attestation(token_resolver)) => {
+                self.resolver_options.check_encoding()?;
+                match self.key_pool_size {
+                    Some(size) => Some(ClientCredentials::AttestationWithKeyPool(
+                        token_resolver,
+                        self.resolver_options.clone(),
+                        Arc::new(KeyPool::new(self.resolver_options.key_type, size)?)
+                    )),
+                    None => Some(ClientCredentials::Attestation(Arc::new(
+                        RaTlsCertResolver::with_options(token_resolver, self.resolver_options)?
+                    )))
+                }
+            }
+        };
+
+        Ok(ClientConfigFactory::new(client_credentials, server_verification, post_handshake, protocol))
+    }
+
+    pub fn build(self) -> Result<RaTlsClient, RaTlsError> {
+        Ok(RaTlsClient::from_config_factory(self.build_config_factory()?))
+    }
+}
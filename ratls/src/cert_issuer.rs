@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use rcgen::{CertificateParams, Issuer, PublicKeyData};
+use rustls::{crypto::ring::sign::any_supported_type, pki_types::{CertificateDer, PrivateKeyDer}, sign::SigningKey};
+use crate::error::RaTlsError;
+use crate::identity::{load_private_key, KeySigner};
+use crate::tools::load_certificates_from_pem;
+
+// Signs the attested certificates instead of their own key, so peers doing
+// plain webpki validation can check them against their roots. The chain
+// starts with the issuing certificate, the intermediates above it follow.
+// It is sent after the attested certificate.
+#[derive(Debug)]
+pub struct CertificateIssuer {
+    issuer: Issuer<'static, KeySigner>,
+    chain: Vec<CertificateDer<'static>>
+}
+
+impl CertificateIssuer {
+    pub fn new(chain: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> Result<Self, RaTlsError> {
+        Self::with_signing_key(chain, any_supported_type(&key)?)
+    }
+
+    // Certificates in PEM, the key in PEM or PKCS#8 DER
+    pub fn from_files(chain_path: impl AsRef<str>, key_path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        Self::new(load_certificates_from_pem(chain_path.as_ref())?, load_private_key(key_path)?)
+    }
+
+    // The issuing key stays with the application, e.g. in an HSM
+    pub fn with_signing_key(chain: Vec<CertificateDer<'static>>, signing_key: Arc<dyn SigningKey>) -> Result<Self, RaTlsError> {
+        let issuer_cert = chain.first().ok_or(RaTlsError::MissingIssuerCertificate)?;
+        let signer = KeySigner::new(signing_key)?;
+
+        if !issuer_cert_matches(issuer_cert, &signer)? {
+            return Err(RaTlsError::IssuerKeyMismatch);
+        }
+
+        let issuer = Issuer::from_ca_cert_der(issuer_cert, signer)?;
+        Ok(Self { issuer, chain })
+    }
+
+    // The attested certificate followed by the chain
+    pub(crate) fn issue(
+        &self,
+        params: &CertificateParams,
+        public_key: &impl PublicKeyData
+    ) -> Result<Vec<CertificateDer<'static>>, RaTlsError> {
+        let cert = params.signed_by(public_key, &self.issuer)?.der().to_owned();
+
+        Ok([cert].into_iter().chain(self.chain.iter().cloned()).collect())
+    }
+}
+
+fn issuer_cert_matches(issuer_cert: &CertificateDer, signer: &KeySigner) -> Result<bool, RaTlsError> {
+    let spki = webpki::EndEntityCert::try_from(issuer_cert)?.subject_public_key_info();
+    Ok(spki.as_ref() == signer.subject_public_key_info().as_slice())
+}
@@ -0,0 +1,215 @@
+use std::{net::IpAddr, time::{Duration, SystemTime}};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName, DnType, ExtendedKeyUsagePurpose, KeyUsagePurpose, SanType};
+use crate::config::{EvidenceExtension, BATCH_PROOF_X509_EXT, KEY_ORIGIN_X509_EXT};
+use crate::error::RaTlsError;
+
+const DEFAULT_COMMON_NAME: &str = "RA-TLS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubjectAttribute {
+    CommonName,
+    Organization,
+    OrganizationalUnit,
+    Country,
+    State,
+    Locality
+}
+
+impl SubjectAttribute {
+    fn dn_type(&self) -> DnType {
+        match self {
+            Self::CommonName => DnType::CommonName,
+            Self::Organization => DnType::OrganizationName,
+            Self::OrganizationalUnit => DnType::OrganizationalUnitName,
+            Self::Country => DnType::CountryName,
+            Self::State => DnType::StateOrProvinceName,
+            Self::Locality => DnType::LocalityName
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUsage {
+    DigitalSignature,
+    ContentCommitment,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement
+}
+
+impl KeyUsage {
+    fn purpose(&self) -> KeyUsagePurpose {
+        match self {
+            Self::DigitalSignature => KeyUsagePurpose::DigitalSignature,
+            Self::ContentCommitment => KeyUsagePurpose::ContentCommitment,
+            Self::KeyEncipherment => KeyUsagePurpose::KeyEncipherment,
+            Self::DataEncipherment => KeyUsagePurpose::DataEncipherment,
+            Self::KeyAgreement => KeyUsagePurpose::KeyAgreement
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtendedKeyUsage {
+    ServerAuth,
+    ClientAuth,
+    // Any other purpose by its OID arcs
+    Other(Vec<u64>)
+}
+
+impl ExtendedKeyUsage {
+    fn purpose(&self) -> ExtendedKeyUsagePurpose {
+        match self {
+            Self::ServerAuth => ExtendedKeyUsagePurpose::ServerAuth,
+            Self::ClientAuth => ExtendedKeyUsagePurpose::ClientAuth,
+            Self::Other(oid) => ExtendedKeyUsagePurpose::Other(oid.clone())
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Extension {
+    oid: Vec<u64>,
+    critical: bool,
+    content: Vec<u8>
+}
+
+// Contents of the generated certificates besides the key and the evidence.
+// The validity starts clock_skew before the certificate is made, so peers
+// with a clock running behind still accept it.
+#[derive(Debug, Clone)]
+pub struct CertificateProfile {
+    subject: Vec<(SubjectAttribute, String)>,
+    dns_names: Vec<String>,
+    ip_addresses: Vec<IpAddr>,
+    validity: Duration,
+    clock_skew: Duration,
+    key_usage: Vec<KeyUsage>,
+    extended_key_usage: Vec<ExtendedKeyUsage>,
+    extensions: Vec<Extension>
+}
+
+impl Default for CertificateProfile {
+    fn default() -> Self {
+        Self {
+            subject: Vec::new(),
+            dns_names: Vec::new(),
+            ip_addresses: Vec::new(),
+            validity: Duration::from_secs(24 * 3600),
+            clock_skew: Duration::from_secs(3600),
+            key_usage: vec![KeyUsage::DigitalSignature],
+            extended_key_usage: vec![ExtendedKeyUsage::ServerAuth, ExtendedKeyUsage::ClientAuth],
+            extensions: Vec::new()
+        }
+    }
+}
+
+impl CertificateProfile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Without any the subject is CN=RA-TLS
+    pub fn with_subject(mut self, attribute: SubjectAttribute, value: impl Into<String>) -> Self {
+        self.subject.push((attribute, value.into()));
+        self
+    }
+
+    // Put next to the hostname requested by the client, if there is one
+    pub fn with_dns_name(mut self, dns_name: impl Into<String>) -> Self {
+        self.dns_names.push(dns_name.into());
+        self
+    }
+
+    pub fn with_ip_address(mut self, ip_address: IpAddr) -> Self {
+        self.ip_addresses.push(ip_address);
+        self
+    }
+
+    // How long the certificate stays valid after it is made
+    pub fn with_validity(mut self, validity: Duration) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    pub(crate) fn validity(&self) -> Duration {
+        self.validity
+    }
+
+    pub fn with_clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    // An empty list leaves the extension out
+    pub fn with_key_usage(mut self, key_usage: Vec<KeyUsage>) -> Self {
+        self.key_usage = key_usage;
+        self
+    }
+
+    pub fn with_extended_key_usage(mut self, extended_key_usage: Vec<ExtendedKeyUsage>) -> Self {
+        self.extended_key_usage = extended_key_usage;
+        self
+    }
+
+    // The content is DER encoded by the caller. The extensions used by this
+    // crate for the evidence can't be set here.
+    pub fn with_extension(mut self, oid: &[u64], critical: bool, content: Vec<u8>) -> Result<Self, RaTlsError> {
+        if reserved_oids()?.iter().any(|reserved| reserved.as_slice() == oid) {
+            return Err(RaTlsError::ReservedExtension(oid.to_vec()));
+        }
+        self.extensions.push(Extension { oid: oid.to_vec(), critical, content });
+        Ok(self)
+    }
+
+    pub(crate) fn params(&self, hostname: Option<&str>) -> Result<CertificateParams, RaTlsError> {
+        let mut params = CertificateParams::default();
+        let now = SystemTime::now();
+
+        params.not_before = now.checked_sub(self.clock_skew).unwrap_or(SystemTime::UNIX_EPOCH).into();
+        params.not_after = now.checked_add(self.validity).ok_or(RaTlsError::InvalidValidity)?.into();
+
+        params.distinguished_name = DistinguishedName::new();
+        if self.subject.is_empty() {
+            params.distinguished_name.push(DnType::CommonName, DEFAULT_COMMON_NAME);
+        }
+        for (attribute, value) in &self.subject {
+            params.distinguished_name.push(attribute.dn_type(), value.as_str());
+        }
+
+        // The requested hostname can be an IP address, that goes to the IP SANs
+        let requested_ip = hostname.and_then(|hostname| hostname.parse::<IpAddr>().ok());
+        let hostname = hostname.filter(|_| requested_ip.is_none());
+
+        let dns_names = hostname
+            .into_iter()
+            .chain(self.dns_names.iter().map(String::as_str).filter(|dns_name| Some(*dns_name) != hostname));
+        for dns_name in dns_names {
+            params.subject_alt_names.push(SanType::DnsName(dns_name.try_into()?));
+        }
+        let ip_addresses = requested_ip
+            .into_iter()
+            .chain(self.ip_addresses.iter().copied().filter(|ip_address| Some(*ip_address) != requested_ip));
+        params.subject_alt_names.extend(ip_addresses.map(SanType::IpAddress));
+
+        params.key_usages = self.key_usage.iter().map(KeyUsage::purpose).collect();
+        params.extended_key_usages = self.extended_key_usage.iter().map(ExtendedKeyUsage::purpose).collect();
+
+        for extension in &self.extensions {
+            let mut custom_extension = CustomExtension::from_oid_content(&extension.oid, extension.content.clone());
+            custom_extension.set_criticality(extension.critical);
+            params.custom_extensions.push(custom_extension);
+        }
+
+        Ok(params)
+    }
+}
+
+fn reserved_oids() -> Result<Vec<Vec<u64>>, RaTlsError> {
+    EvidenceExtension::ALL
+        .iter()
+        .map(|ext| ext.oid())
+        .chain([&*BATCH_PROOF_X509_EXT, &*KEY_ORIGIN_X509_EXT])
+        .map(|oid| Ok(oid.as_vec::<u64>()?))
+        .collect()
+}
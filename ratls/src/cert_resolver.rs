@@ -1,73 +1,358 @@
 use log::{debug, info, error};
-use rcgen::{CertificateParams, KeyPair, CustomExtension, date_time_ymd, DistinguishedName};
+use rcgen::{CertificateParams, KeyPair, CustomExtension, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, PKCS_ED25519};
 use rsa::RsaPrivateKey;
 use rustls::{client::ResolvesClientCert,
              server::ResolvesServerCert,
-             sign::CertifiedKey,
+             sign::{CertifiedKey, SigningKey},
              crypto::ring::sign::any_supported_type,
-             pki_types::PrivateKeyDer,
+             pki_types::{CertificateDer, PrivateKeyDer},
 };
-use std::sync::Arc;
+use std::{net::IpAddr, sync::{Arc, Mutex}, time::SystemTime};
 use rand::rngs::OsRng;
-use pkcs8::{EncodePrivateKey, EncodePublicKey};
-use crate::{error::RaTlsError, tools::hash_realm_challenge, config::CCA_TOKEN_X509_EXT};
+use pkcs8::EncodePrivateKey;
+use crate::{error::RaTlsError, tools::hash_realm_challenge, config::{EvidenceExtension, BATCH_PROOF_X509_EXT, CHALLENGE_HINT_PREFIX, KEY_ORIGIN_X509_EXT}};
+use crate::batch::{TokenBatcher, TokenBatching};
+use crate::cert_issuer::CertificateIssuer;
+use crate::cert_profile::CertificateProfile;
+use crate::channel_binding::ExchangedTokens;
+use crate::identity::{load_private_key, KeyOrigin, ResolverKey};
+use crate::key_rotation::{KeyRotationPolicy, RotatingKey};
+use crate::cmw::{self, EvidenceEncoding};
+use crate::rate_limit::TokenRateLimiter;
+use crate::sni::decode_server_name;
 use crate::token_resolver::InternalTokenResolver;
 use base64::{Engine, engine::general_purpose::STANDARD as b64};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    // Key size in bits
+    Rsa(usize),
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519
+}
+
+impl Default for KeyType {
+    fn default() -> Self {
+        Self::Rsa(2048)
+    }
+}
+
+impl KeyType {
+    pub(crate) fn generate(&self) -> Result<KeyPair, RaTlsError> {
+        match self {
+            // ring can't generate RSA keys, RustCrypto is used instead
+            Self::Rsa(key_size) => {
+                let private_key = RsaPrivateKey::new(&mut OsRng, *key_size)?;
+                Ok(KeyPair::try_from(private_key.to_pkcs8_der()?.as_bytes())?)
+            },
+            Self::EcdsaP256 => Ok(KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?),
+            Self::EcdsaP384 => Ok(KeyPair::generate_for(&PKCS_ECDSA_P384_SHA384)?),
+            Self::Ed25519 => Ok(KeyPair::generate_for(&PKCS_ED25519)?)
+        }
+    }
+}
+
+// Certificate without evidence, used as the TLS identity when the evidence
+// is sent after the handshake. It is made again once half of its validity
+// has passed, so long-running servers never present an expired one.
+#[derive(Debug)]
+pub(crate) struct PlainCertResolver {
+    key: ResolverKey,
+    options: ResolverOptions,
+    // The certificate and when it is due for renewal
+    current: Mutex<(Arc<CertifiedKey>, Option<SystemTime>)>
+}
+
+impl PlainCertResolver {
+    pub(crate) fn new(key: ResolverKey, options: ResolverOptions) -> Result<Self, RaTlsError> {
+        let current = Mutex::new(Self::create(&key, &options)?);
+        Ok(Self { key, options, current })
+    }
+
+    fn create(key: &ResolverKey, options: &ResolverOptions) -> Result<(Arc<CertifiedKey>, Option<SystemTime>), RaTlsError> {
+        let renew_at = SystemTime::now().checked_add(options.profile.validity() / 2);
+        let params = options.profile.params(None)?;
+        let certs = issue_cert(&params, key, options.issuer.as_deref())?;
+
+        Ok((Arc::new(CertifiedKey::new(certs, key.signing_key())), renew_at))
+    }
+
+    fn current(&self) -> Option<Arc<CertifiedKey>> {
+        let mut current = self.current.lock().ok()?;
+
+        if current.1.is_some_and(|renew_at| SystemTime::now() >= renew_at) {
+            match Self::create(&self.key, &self.options) {
+                Ok(renewed) => *current = renewed,
+                // The old one is still valid for a while
+                Err(err) => error!("Failed to renew the certificate: {:?}", err)
+            }
+        }
+        Some(current.0.clone())
+    }
+}
+
+impl ResolvesServerCert for PlainCertResolver {
+    fn resolve(&self, _client_hello: rustls::server::ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+}
+
+// Self-signed unless there is an issuer, then the chain follows
+fn issue_cert(
+    params: &CertificateParams,
+    key: &ResolverKey,
+    issuer: Option<&CertificateIssuer>
+) -> Result<Vec<CertificateDer<'static>>, RaTlsError> {
+    match issuer {
+        Some(issuer) => issuer.issue(params, &key.signer),
+        None => Ok(vec![params.self_signed(&key.signer)?.der().to_owned()])
+    }
+}
+
+// Everything needed to create a resolver, kept by the builders
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ResolverOptions {
+    pub(crate) key_type: KeyType,
+    pub(crate) evidence_extension: EvidenceExtension,
+    pub(crate) evidence_encoding: EvidenceEncoding,
+    pub(crate) profile: Arc<CertificateProfile>,
+    pub(crate) issuer: Option<Arc<CertificateIssuer>>
+}
+
+impl ResolverOptions {
+    pub(crate) fn check_encoding(&self) -> Result<(), RaTlsError> {
+        self.evidence_extension.check_encoding(self.evidence_encoding)
+    }
+}
+
 #[derive(Debug)]
 pub struct RaTlsCertResolver {
     token_resolver: Arc<dyn InternalTokenResolver>,
-    private_key: RsaPrivateKey
+    key: Arc<RotatingKey>,
+    evidence_extension: EvidenceExtension,
+    evidence_encoding: EvidenceEncoding,
+    profile: Arc<CertificateProfile>,
+    issuer: Option<Arc<CertificateIssuer>>,
+    rate_limiter: Option<Arc<TokenRateLimiter>>,
+    // The batch shares the token and the key the token is bound to
+    batcher: Option<TokenBatcher<(Vec<u8>, Arc<ResolverKey>)>>
 }
 
 impl RaTlsCertResolver {
     pub fn from_token_resolver(token_resolver: Arc<dyn InternalTokenResolver>) -> Result<Self, RaTlsError> {
-        let key_size = 2048;
+        Self::with_key_type(token_resolver, KeyType::default())
+    }
+
+    pub fn with_key_type(token_resolver: Arc<dyn InternalTokenResolver>, key_type: KeyType) -> Result<Self, RaTlsError> {
+        info!("Generating {:?} key.", key_type);
+        let key_pair = key_type.generate()?;
+        info!("Finished generating {:?} key.", key_type);
 
-        info!("Generating RSA {}bit key.", key_size);
-        let private_key = RsaPrivateKey::new(&mut OsRng, key_size)?;
-        info!("Finished generating RSA key.");
+        Self::with_key_pair(token_resolver, key_type, key_pair)
+    }
+
+    pub(crate) fn with_key_pair(token_resolver: Arc<dyn InternalTokenResolver>, key_type: KeyType, key_pair: KeyPair) -> Result<Self, RaTlsError> {
+        Ok(Self::with_resolver_key(token_resolver, key_type, ResolverKey::generated(key_pair)?))
+    }
+
+    // PKCS#8 key in a PEM or DER file, kept across restarts
+    pub fn from_key_file(token_resolver: Arc<dyn InternalTokenResolver>, path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        Self::from_private_key(token_resolver, load_private_key(path)?)
+    }
 
-        Ok(Self {
+    pub fn from_private_key(token_resolver: Arc<dyn InternalTokenResolver>, private_key: PrivateKeyDer<'static>) -> Result<Self, RaTlsError> {
+        Self::with_signing_key(token_resolver, any_supported_type(&private_key)?, KeyOrigin::Persistent)
+    }
+
+    // The key never leaves the application, e.g. it signs with an HSM. It has
+    // to report its SubjectPublicKeyInfo through public_key().
+    pub fn from_signing_key(token_resolver: Arc<dyn InternalTokenResolver>, signing_key: Arc<dyn SigningKey>) -> Result<Self, RaTlsError> {
+        Self::with_signing_key(token_resolver, signing_key, KeyOrigin::External)
+    }
+
+    pub(crate) fn with_signing_key(
+        token_resolver: Arc<dyn InternalTokenResolver>,
+        signing_key: Arc<dyn SigningKey>,
+        origin: KeyOrigin
+    ) -> Result<Self, RaTlsError> {
+        Ok(Self::with_resolver_key(token_resolver, KeyType::default(), ResolverKey::with_origin(signing_key, origin)?))
+    }
+
+    fn with_resolver_key(token_resolver: Arc<dyn InternalTokenResolver>, key_type: KeyType, key: ResolverKey) -> Self {
+        Self {
             token_resolver,
-            private_key
-        })
-    }
-
-    fn create_cert(&self, challenge: String) -> Result<Arc<CertifiedKey>, RaTlsError> {
-        debug!("Received challenge {}", challenge);
-        let realm_challenge = hash_realm_challenge(
-            b64.decode(challenge)?.as_slice(),
-            self.private_key
-                .to_public_key()
-                .to_public_key_der()?
-                .as_bytes()
-        );
-
-        let token = self
-            .token_resolver
-            .resolve(&realm_challenge)
-            .inspect_err(|_| error!("Failed to acquire token from the token_resolver"))?;
-        let pkcs8_privkey = self.private_key.to_pkcs8_der()?;
-        // We are decoding DER created by RustCrypto,
-        // this has no right to fail.
-        let privkey = PrivateKeyDer::try_from(pkcs8_privkey.as_bytes()).unwrap();
-        let mut params = CertificateParams::default();
-        let key_pair = KeyPair::try_from(pkcs8_privkey.as_bytes())?;
-
-        params.not_before = date_time_ymd(2021, 5, 19);
-        params.not_after = date_time_ymd(4096, 1, 1);
-        params.distinguished_name = DistinguishedName::new();
+            key: Arc::new(RotatingKey::new(key_type, key)),
+            evidence_extension: EvidenceExtension::default(),
+            evidence_encoding: EvidenceEncoding::default(),
+            profile: Arc::default(),
+            issuer: None,
+            rate_limiter: None,
+            batcher: None
+        }
+    }
+
+    pub(crate) fn with_options(token_resolver: Arc<dyn InternalTokenResolver>, options: ResolverOptions) -> Result<Self, RaTlsError> {
+        Ok(Self::with_key_type(token_resolver, options.key_type)?.apply_options(options))
+    }
+
+    // Same as with_options, with a key generated beforehand
+    pub(crate) fn with_options_and_key(
+        token_resolver: Arc<dyn InternalTokenResolver>,
+        options: ResolverOptions,
+        key_pair: KeyPair
+    ) -> Result<Self, RaTlsError> {
+        Ok(Self::with_key_pair(token_resolver, options.key_type, key_pair)?.apply_options(options))
+    }
+
+    // Everything but the key type, for resolvers with a supplied key
+    pub(crate) fn apply_options(mut self, options: ResolverOptions) -> Self {
+        self.profile = options.profile;
+        self.issuer = options.issuer;
+        self.with_evidence_extension(options.evidence_extension)
+            .with_evidence_encoding(options.evidence_encoding)
+    }
+
+    pub fn with_evidence_extension(mut self, evidence_extension: EvidenceExtension) -> Self {
+        self.evidence_extension = evidence_extension;
+        self
+    }
+
+    pub fn with_evidence_encoding(mut self, evidence_encoding: EvidenceEncoding) -> Self {
+        self.evidence_encoding = evidence_encoding;
+        self
+    }
+
+    pub fn with_certificate_profile(mut self, profile: CertificateProfile) -> Self {
+        self.profile = Arc::new(profile);
+        self
+    }
+
+    // The certificates are signed by the issuer and sent with its chain.
+    // Clients that don't send a challenge get a certificate without evidence,
+    // they can only check the chain.
+    pub fn with_certificate_issuer(mut self, issuer: CertificateIssuer) -> Self {
+        self.issuer = Some(Arc::new(issuer));
+        self
+    }
+
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Option<Arc<TokenRateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    // One token is shared by the certificates created within the batching
+    // window, only peers of this version can verify them.
+    pub fn with_token_batching(mut self, batching: TokenBatching) -> Self {
+        self.batcher = Some(TokenBatcher::new(batching));
+        self
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn batches_tokens(&self) -> bool {
+        self.batcher.is_some()
+    }
+
+    // Replaces the key according to the policy, the certificates made after
+    // the rotation carry evidence bound to the new key. Only generated keys
+    // are rotated, KeyRotationUnsupported is returned for the others.
+    pub fn with_key_rotation(mut self, policy: KeyRotationPolicy) -> Result<Self, RaTlsError> {
+        // The key is not shared with anything before the resolver is built
+        Arc::get_mut(&mut self.key)
+            .ok_or(RaTlsError::KeyRotationUnsupported)?
+            .set_policy(policy)?;
+        Ok(self)
+    }
+
+    // Resolver for a single connection, the peer address is accounted by the
+    // rate limiter and the token sent to the peer is recorded.
+    pub(crate) fn for_connection(self: &Arc<Self>, peer: Option<IpAddr>, tokens: Arc<ExchangedTokens>) -> Arc<ConnectionCertResolver> {
+        Arc::new(ConnectionCertResolver { resolver: self.clone(), peer, tokens })
+    }
+
+    // The hostname, if known, is put into the certificate so the peer can
+    // check it along with the evidence. The raw token is returned with it.
+    fn create_cert(
+        &self,
+        challenge: &[u8],
+        hostname: Option<&str>,
+        source: Option<IpAddr>
+    ) -> Result<(Arc<CertifiedKey>, Vec<u8>), RaTlsError> {
+        debug!("Received challenge {:02X?}", challenge);
+        self.evidence_extension.check_encoding(self.evidence_encoding)?;
+        let (token, key, proof) = match &self.batcher {
+            None => {
+                let key = self.key.current()?;
+                (self.resolve_token(challenge, &key, source)?, key, None)
+            },
+            Some(batcher) => {
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter.take_token(source)?;
+                }
+                // Only the resolver call of the whole batch takes a slot
+                let ((token, key), proof) = batcher.resolve(challenge, |root| {
+                    let key = self.key.current()?;
+                    Ok((self.resolve_token(root, &key, None)?, key))
+                })?;
+                (token, key, Some(proof.encode()?))
+            }
+        };
+        self.key.record_use(&key);
+        let evidence = cmw::wrap(self.token_resolver.media_type(), token.clone(), self.evidence_encoding)?;
+        let mut params = self.profile.params(self.requested_hostname(hostname))?;
 
         params.custom_extensions.push(CustomExtension::from_oid_content(
-            CCA_TOKEN_X509_EXT.as_vec::<u64>()?.as_slice(),
-            token
+            self.evidence_extension.oid().as_vec::<u64>()?.as_slice(),
+            evidence
         ));
+        if let Some(proof) = proof {
+            params.custom_extensions.push(CustomExtension::from_oid_content(
+                BATCH_PROOF_X509_EXT.as_vec::<u64>()?.as_slice(),
+                proof
+            ));
+        }
 
-        let cert = params.self_signed(&key_pair)?.der().to_owned();
-        let key = any_supported_type(&privkey)?;
+        Ok((self.certified_key(params, &key)?, token))
+    }
+
+    // For clients doing plain webpki validation, only with an issuer
+    fn create_plain_cert(&self, hostname: Option<&str>) -> Result<Arc<CertifiedKey>, RaTlsError> {
+        let key = self.key.current()?;
+        self.key.record_use(&key);
+
+        self.certified_key(self.profile.params(self.requested_hostname(hostname))?, &key)
+    }
 
-        Ok(Arc::new(CertifiedKey::new(vec![cert], key)))
+    // A CA vouches only for the names in the profile, the hostname asked
+    // for by the client is put into self-signed certificates only.
+    fn requested_hostname<'a>(&self, hostname: Option<&'a str>) -> Option<&'a str> {
+        hostname.filter(|_| self.issuer.is_none())
+    }
+
+    fn certified_key(&self, mut params: CertificateParams, key: &ResolverKey) -> Result<Arc<CertifiedKey>, RaTlsError> {
+        params.custom_extensions.push(CustomExtension::from_oid_content(
+            KEY_ORIGIN_X509_EXT.as_vec::<u64>()?.as_slice(),
+            key.origin.encode()?
+        ));
+        let certs = issue_cert(&params, key, self.issuer.as_deref())?;
+
+        Ok(Arc::new(CertifiedKey::new(certs, key.signing_key())))
+    }
+}
+
+impl RaTlsCertResolver {
+    // The token for a challenge, or for the Merkle root of batched ones
+    fn resolve_token(&self, challenge: &[u8], key: &ResolverKey, source: Option<IpAddr>) -> Result<Vec<u8>, RaTlsError> {
+        let _permit = match &self.rate_limiter {
+            Some(rate_limiter) if self.batcher.is_some() => Some(rate_limiter.take_slot()?),
+            Some(rate_limiter) => Some(rate_limiter.acquire(source)?),
+            None => None
+        };
+        let realm_challenge = hash_realm_challenge(challenge, &key.public_key_der);
+
+        self.token_resolver
+            .resolve(&realm_challenge)
+            .inspect_err(|_| error!("Failed to acquire token from the token_resolver"))
     }
 }
 
@@ -81,25 +366,240 @@ impl ResolvesClientCert for RaTlsCertResolver {
             acceptable_issuers: &[&[u8]],
             _sigschemes: &[rustls::SignatureScheme],
         ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        self.resolve_client_cert(acceptable_issuers, None)
+    }
+}
 
-        if acceptable_issuers.len() != 1 {
-            return None;
-        }
+impl RaTlsCertResolver {
+    fn resolve_client_cert(&self, acceptable_issuers: &[&[u8]], tokens: Option<&ExchangedTokens>) -> Option<Arc<CertifiedKey>> {
+        let hint = acceptable_issuers
+            .iter()
+            .find_map(|issuer| issuer.strip_prefix(CHALLENGE_HINT_PREFIX));
 
-        if let Ok(challenge) = String::from_utf8(acceptable_issuers[0].to_owned()) {
-            self.create_cert(challenge).ok()
-        } else {
-            None
+        // Older servers send the challenge without the prefix as the only issuer
+        let hint = match (hint, acceptable_issuers) {
+            (Some(hint), _) => hint,
+            (None, [issuer]) => issuer,
+            (None, _) => return None
+        };
+
+        let challenge = b64.decode(hint).ok()?;
+        let (certified_key, token) = self.create_cert(&challenge, None, None).ok()?;
+        record_token(tokens, token);
+        Some(certified_key)
+    }
+
+    fn resolve_server_cert(
+        &self,
+        server_name: Option<&str>,
+        source: Option<IpAddr>,
+        tokens: Option<&ExchangedTokens>
+    ) -> Option<Arc<CertifiedKey>> {
+        match server_name.map(decode_server_name) {
+            Some(Ok((challenge, hostname))) => {
+                let (certified_key, token) = self.create_cert(&challenge, Some(&hostname), source).ok()?;
+                record_token(tokens, token);
+                Some(certified_key)
+            },
+            _ if self.issuer.is_some() => {
+                debug!("No challenge from the client, sending a certificate without evidence");
+                self.create_plain_cert(server_name).ok()
+            },
+            _ => {
+                error!("Server name does not carry a challenge");
+                None
+            }
         }
     }
 }
 
+fn record_token(tokens: Option<&ExchangedTokens>, token: Vec<u8>) {
+    if let Some(tokens) = tokens {
+        let _ = tokens.local.set(token);
+    }
+}
+
 impl ResolvesServerCert for RaTlsCertResolver {
     fn resolve(&self, client_hello: rustls::server::ClientHello) -> Option<Arc<rustls::sign::CertifiedKey>> {
-        if let Some(challenge) = client_hello.server_name() {
-            self.create_cert(challenge.to_owned()).ok()
-        } else {
-            None
+        self.resolve_server_cert(client_hello.server_name(), None, None)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ConnectionCertResolver {
+    resolver: Arc<RaTlsCertResolver>,
+    peer: Option<IpAddr>,
+    tokens: Arc<ExchangedTokens>
+}
+
+impl ConnectionCertResolver {
+    // Creates the certificate before the handshake, for servers that must
+    // not block while driving it
+    #[cfg(feature = "tokio")]
+    pub(crate) fn resolve_now(&self, server_name: Option<&str>) -> Arc<ResolvedCert> {
+        Arc::new(ResolvedCert(self.resolver.resolve_server_cert(server_name, self.peer, Some(&self.tokens))))
+    }
+}
+
+impl ResolvesServerCert for ConnectionCertResolver {
+    fn resolve(&self, client_hello: rustls::server::ClientHello) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        self.resolver.resolve_server_cert(client_hello.server_name(), self.peer, Some(&self.tokens))
+    }
+}
+
+// Certificate created for the ClientHello before the handshake, None makes
+// the handshake fail
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub(crate) struct ResolvedCert(Option<Arc<CertifiedKey>>);
+
+#[cfg(feature = "tokio")]
+impl ResolvesServerCert for ResolvedCert {
+    fn resolve(&self, _client_hello: rustls::server::ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.0.clone()
+    }
+}
+
+impl ResolvesClientCert for ConnectionCertResolver {
+    fn has_certs(&self) -> bool {
+        true
+    }
+
+    fn resolve(
+            &self,
+            acceptable_issuers: &[&[u8]],
+            _sigschemes: &[rustls::SignatureScheme],
+        ) -> Option<Arc<rustls::sign::CertifiedKey>> {
+        self.resolver.resolve_client_cert(acceptable_issuers, Some(&self.tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{BasicConstraints, IsCa};
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName, UnixTime};
+    use std::time::Duration;
+    use rustls::server::danger::ClientCertVerifier;
+    use crate::cert_verifier::RaTlsCertVeryfier;
+    use crate::evidence::EvidenceVerifiers;
+    use crate::sample_evidence::{SampleEvidenceResolver, SampleEvidenceVerifier};
+    use crate::token_verifier::SkipVerification;
+    use super::*;
+
+    fn issuer() -> CertificateIssuer {
+        let key_pair = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = params.self_signed(&key_pair).unwrap();
+
+        CertificateIssuer::new(
+            vec![cert.der().to_owned()],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()))
+        ).unwrap()
+    }
+
+    fn resolver() -> RaTlsCertResolver {
+        let token_resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        RaTlsCertResolver::with_key_type(token_resolver, KeyType::EcdsaP256)
+            .unwrap()
+            .with_certificate_profile(CertificateProfile::new().with_dns_name("service.example.com"))
+    }
+
+    fn valid_for(certified_key: &CertifiedKey, name: &'static str) -> bool {
+        webpki::EndEntityCert::try_from(&certified_key.cert[0])
+            .unwrap()
+            .verify_is_valid_for_subject_name(&ServerName::try_from(name).unwrap())
+            .is_ok()
+    }
+
+    #[test]
+    fn issued_certificates_only_carry_profile_names() {
+        let resolver = resolver().with_certificate_issuer(issuer());
+
+        let plain = resolver.create_plain_cert(Some("attacker.example.com")).unwrap();
+        let (attested, _) = resolver.create_cert(&[0; 64], Some("attacker.example.com"), None).unwrap();
+
+        for certified_key in [plain, attested] {
+            assert_eq!(certified_key.cert.len(), 2);
+            assert!(valid_for(&certified_key, "service.example.com"));
+            assert!(!valid_for(&certified_key, "attacker.example.com"));
+        }
+    }
+
+    #[test]
+    fn self_signed_certificates_carry_requested_hostname() {
+        let (certified_key, _) = resolver().create_cert(&[0; 64], Some("client.example.com"), None).unwrap();
+
+        assert_eq!(certified_key.cert.len(), 1);
+        assert!(valid_for(&certified_key, "service.example.com"));
+        assert!(valid_for(&certified_key, "client.example.com"));
+    }
+
+    #[test]
+    fn bare_tokens_only_go_into_the_legacy_extension() {
+        let token_resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let evidence_verifiers = Arc::new(EvidenceVerifiers::new()
+            .with_verifier(Arc::new(SampleEvidenceVerifier::new(vec![token_resolver.public_key().to_vec()]))));
+
+        for extension in EvidenceExtension::ALL {
+            for encoding in [EvidenceEncoding::Raw, EvidenceEncoding::CmwCbor, EvidenceEncoding::CmwJson] {
+                let verifier = RaTlsCertVeryfier::with_evidence_verifiers(Arc::new(SkipVerification), evidence_verifiers.clone());
+                let resolver = RaTlsCertResolver::with_key_type(token_resolver.clone(), KeyType::EcdsaP256)
+                    .unwrap()
+                    .with_evidence_extension(extension)
+                    .with_evidence_encoding(encoding);
+                let result = resolver.create_cert(verifier.challenge(), None, None);
+
+                if extension != EvidenceExtension::Legacy && encoding == EvidenceEncoding::Raw {
+                    assert!(matches!(result, Err(RaTlsError::RawEvidenceInCmwExtension)), "{:?} {:?}", extension, encoding);
+                    continue;
+                }
+                let (certified_key, _) = result.unwrap();
+                assert!(verifier.verify_client_cert(&certified_key.cert[0], &[], UnixTime::now()).is_ok(), "{:?} {:?}", extension, encoding);
+            }
         }
     }
+
+    #[test]
+    fn plain_certificate_is_renewed_halfway_through_its_validity() {
+        let plain = |validity| {
+            let options = ResolverOptions {
+                profile: Arc::new(CertificateProfile::new().with_validity(validity)),
+                ..Default::default()
+            };
+            let key = ResolverKey::generated(KeyType::EcdsaP256.generate().unwrap()).unwrap();
+            PlainCertResolver::new(key, options).unwrap()
+        };
+
+        let long_lived = plain(Duration::from_secs(24 * 3600));
+        assert!(Arc::ptr_eq(&long_lived.current().unwrap(), &long_lived.current().unwrap()));
+
+        let expiring = plain(Duration::ZERO);
+        assert!(!Arc::ptr_eq(&expiring.current().unwrap(), &expiring.current().unwrap()));
+    }
+
+    #[test]
+    fn client_certificate_answers_prefixed_and_bare_challenges() {
+        let token_resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let resolver = RaTlsCertResolver::with_key_type(token_resolver.clone(), KeyType::EcdsaP256).unwrap();
+        let evidence_verifiers = Arc::new(EvidenceVerifiers::new()
+            .with_verifier(Arc::new(SampleEvidenceVerifier::new(vec![token_resolver.public_key().to_vec()]))));
+        let verifier = RaTlsCertVeryfier::with_evidence_verifiers(Arc::new(SkipVerification), evidence_verifiers);
+        let challenge = verifier.b64_challenge();
+        let prefixed = [CHALLENGE_HINT_PREFIX, challenge.as_bytes()].concat();
+        let verified = |certified_key: Arc<CertifiedKey>| verifier
+            .verify_client_cert(&certified_key.cert[0], &[], UnixTime::now())
+            .is_ok();
+
+        // Next to the names of real CAs
+        let certified_key = resolver.resolve_client_cert(&[b"CN=Example CA", &prefixed], None).unwrap();
+        assert!(verified(certified_key));
+
+        // Older servers send the bare challenge as the only issuer
+        let certified_key = resolver.resolve_client_cert(&[challenge.as_bytes()], None).unwrap();
+        assert!(verified(certified_key));
+
+        // Without the prefix a challenge can't be told apart from a CA name
+        assert!(resolver.resolve_client_cert(&[b"CN=Example CA", challenge.as_bytes()], None).is_none());
+    }
 }
@@ -1,15 +1,21 @@
-use std::sync::Arc;
-use log::{error, info};
-use pkcs8::EncodePublicKey;
+use std::sync::{Arc, OnceLock};
+use log::error;
 use rand::RngCore;
 use base64::{Engine, engine::general_purpose::STANDARD as b64};
-use rust_rsi::{verify_token, print_token, RealmClaims};
 use rustls::pki_types::{ServerName, UnixTime};
 use x509_certificate::X509Certificate;
-use crate::{token_verifier::InternalTokenVerifier, config::CCA_TOKEN_X509_EXT, tools::hash_realm_challenge};
+use crate::cmw::{self, Evidence};
+use crate::evidence::EvidenceVerifiers;
+use crate::sni::decode_server_name;
+use crate::{attestation::PeerAttestation, token_verifier::InternalTokenVerifier, tools::hash_realm_challenge};
+use crate::config::{EvidenceExtension, BATCH_PROOF_X509_EXT, CHALLENGE_HINT_PREFIX, KEY_ORIGIN_X509_EXT};
+use crate::batch::InclusionProof;
+use crate::identity::KeyOrigin;
 use rustls::{client::danger::{ServerCertVerified, ServerCertVerifier}, crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms}, pki_types::CertificateDer, server::danger::{ClientCertVerified, ClientCertVerifier}, DistinguishedName, Error, SignatureScheme};
 use webpki::ring as webpki_algs;
-use crate::error::RaTlsError;
+use crate::{error::RaTlsError, failure::AttestationReport};
+use rustls::{AlertDescription, CertificateError, OtherError, RootCertStore};
+use webpki::KeyUsage;
 
 // Copied from rustls v0.21 implementation of WebPkiVerifier
 static SUPPORTED_SIG_SCHEMES: [SignatureScheme; 9] = [
@@ -87,69 +93,237 @@ static SUPPORTED_SIG_ALGS: WebPkiSupportedAlgorithms = WebPkiSupportedAlgorithms
 #[derive(Debug)]
 pub struct RaTlsCertVeryfier {
     token_verifier: Arc<dyn InternalTokenVerifier>,
+    evidence_verifiers: Arc<EvidenceVerifiers>,
     challenge: [u8; 64],
-    root_subjects: Vec<DistinguishedName>
+    root_subjects: Vec<DistinguishedName>,
+    client_cert_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    chain_anchors: Option<Arc<RootCertStore>>,
+    peer_attestation: OnceLock<Arc<PeerAttestation>>,
+    failure: OnceLock<AttestationReport>
 }
 
 impl RaTlsCertVeryfier {
     pub fn from_token_verifier(token_verifier: Arc<dyn InternalTokenVerifier>) -> Self {
+        Self::with_evidence_verifiers(token_verifier, Arc::new(EvidenceVerifiers::new()))
+    }
+
+    pub fn with_evidence_verifiers(token_verifier: Arc<dyn InternalTokenVerifier>, evidence_verifiers: Arc<EvidenceVerifiers>) -> Self {
         let mut challenge = [0u8; 64];
         rand::thread_rng().fill_bytes(&mut challenge);
         let root_subjects = vec![
-            DistinguishedName::from(b64.encode(challenge).as_bytes().to_owned())
+            DistinguishedName::from([CHALLENGE_HINT_PREFIX, b64.encode(challenge).as_bytes()].concat())
         ];
 
-        Self { token_verifier, challenge, root_subjects}
+        Self {
+            token_verifier,
+            evidence_verifiers,
+            challenge,
+            root_subjects,
+            client_cert_verifier: None,
+            chain_anchors: None,
+            peer_attestation: OnceLock::new(),
+            failure: OnceLock::new()
+        }
+    }
+
+    // Client certificates without evidence are passed to the given verifier,
+    // its CA names are advertised next to the challenge.
+    pub fn with_client_cert_verifier(mut self, client_cert_verifier: Arc<dyn ClientCertVerifier>) -> Self {
+        self.root_subjects.extend_from_slice(client_cert_verifier.root_hint_subjects());
+        self.client_cert_verifier = Some(client_cert_verifier);
+        self
+    }
+
+    // Attested certificates must also chain to these anchors, as for peers
+    // that only do webpki validation.
+    pub fn with_chain_anchors(mut self, chain_anchors: Arc<RootCertStore>) -> Self {
+        self.chain_anchors = Some(chain_anchors);
+        self
     }
 
     pub fn b64_challenge(&self) -> String {
         b64.encode(self.challenge)
     }
 
-    fn fetch_token<'a>(&self, cert: &'a X509Certificate) -> Result<&'a [u8], RaTlsError> {
+    pub(crate) fn challenge(&self) -> &[u8] {
+        &self.challenge
+    }
+
+    // Claims of the peer, set only after its certificate passed verification.
+    pub fn peer_attestation(&self) -> Option<Arc<PeerAttestation>> {
+        self.peer_attestation.get().cloned()
+    }
+
+    // Why the peer was refused, set only if its certificate failed verification.
+    pub fn failure(&self) -> Option<AttestationReport> {
+        self.failure.get().cloned()
+    }
+
+    // Records the failure and picks the rustls error giving the right alert.
+    fn reject(&self, err: RaTlsError) -> Error {
+        let report = AttestationReport::from(&err);
+        error!("Attestation failed: {}", report);
+        let _ = self.failure.set(report.clone());
+
+        match report.alert() {
+            AlertDescription::BadCertificate => Error::InvalidCertificate(CertificateError::BadEncoding),
+            AlertDescription::CertificateUnknown => Error::InvalidCertificate(CertificateError::Other(OtherError(Arc::new(err)))),
+            _ => Error::General(report.to_string())
+        }
+    }
+
+    fn fetch_token(&self, cert: &X509Certificate) -> Result<Evidence, RaTlsError> {
+        let evidence_oids = evidence_oids()?;
+
         for ext in cert.iter_extensions() {
-            if ext.id.0.as_ref() == CCA_TOKEN_X509_EXT.as_raw()?.as_slice() {
-                return ext.value.as_slice().ok_or(RaTlsError::CannotExtractTokenFromExtension);
+            if let Some((extension, _)) = evidence_oids.iter().find(|(_, oid)| ext.id.0.as_ref() == oid.as_slice()) {
+                let data = ext.value.as_slice().ok_or(RaTlsError::CannotExtractTokenFromExtension)?;
+                return unwrap_evidence(*extension, data);
             }
         }
         error!("Token is missing in certificate");
         Err(RaTlsError::MissingTokenInCertificate)
     }
 
+    fn verify_chain(
+        &self,
+        cert_der: &CertificateDer,
+        intermediates: &[CertificateDer],
+        now: UnixTime,
+        usage: KeyUsage
+    ) -> Result<(), RaTlsError> {
+        let Some(chain_anchors) = &self.chain_anchors else {
+            return Ok(());
+        };
+
+        webpki::EndEntityCert::try_from(cert_der)?
+            .verify_for_usage(SUPPORTED_SIG_ALGS.all, &chain_anchors.roots, intermediates, now, usage, None, None)
+            .inspect_err(|err| error!("Certificate chain is not trusted: {:?}", err))?;
+
+        Ok(())
+    }
+
     fn verify_cert(&self, cert_der: &CertificateDer) -> Result<(), RaTlsError> {
         let cert = X509Certificate::from_der(cert_der)?;
-        let pubkey = cert.to_public_key_der()?;
-        let raw_token = self.fetch_token(&cert)?;
-        let token = verify_token(raw_token, None).inspect_err(|_| {error!("Token verification failed")})?;
-        let realm_claims = RealmClaims::from_raw_claims(&token.realm_claims.token_claims, &token.realm_claims.measurement_claims)?;
+        // Take the SubjectPublicKeyInfo verbatim, re-encoding it could change
+        // the bytes (e.g. add NULL parameters for Ed25519) and break the binding.
+        let pubkey = webpki::EndEntityCert::try_from(cert_der)?.subject_public_key_info();
+        let evidence = self.fetch_token(&cert)?;
+        // Evidence shared by a batch is bound to the Merkle root of the challenges
+        let challenge = match fetch_inclusion_proof(&cert)? {
+            Some(proof) => proof.root(&self.challenge)?.to_vec(),
+            None => self.challenge.to_vec()
+        };
         let hash = hash_realm_challenge(
-            self.challenge.as_slice(),
-            pubkey.as_bytes()
+            challenge.as_slice(),
+            pubkey.as_ref()
         );
+        let attestation = self.evidence_verifiers
+            .verify_evidence(evidence, &hash, self.token_verifier.as_ref())?
+            .with_key_origin(fetch_key_origin(&cert)?);
+
+        let _ = self.peer_attestation.set(Arc::new(attestation));
+
+        Ok(())
+    }
+
+    // The server name sent by the client carries the challenge and the real
+    // hostname, the latter has to be present in the server certificate.
+    fn verify_server_name(&self, cert_der: &CertificateDer, server_name: &ServerName) -> Result<(), RaTlsError> {
+        let ServerName::DnsName(dns_name) = server_name else {
+            return Err(RaTlsError::InvalidServerName);
+        };
+        let (challenge, hostname) = decode_server_name(dns_name.as_ref())?;
 
-        if hash != realm_claims.challenge {
-            error!("Challenge mismatch, expected:\n{:02X?}\nand got:\n{:02X?}", self.challenge, realm_claims.challenge);
-            #[cfg(not(feature = "disable-challenge"))]
+        if challenge != self.challenge {
+            error!("Server name carries a different challenge");
             return Err(RaTlsError::InvalidChallenge);
         }
 
-        info!("Received client CCA token:");
-        print_token(&token);
+        // Either a DNS name or an IP address, webpki checks the matching SAN
+        let hostname = ServerName::try_from(hostname)?;
+        webpki::EndEntityCert::try_from(cert_der)?
+            .verify_is_valid_for_subject_name(&hostname)
+            .inspect_err(|_| error!("Certificate is not valid for {:?}", hostname))?;
+
+        Ok(())
+    }
+}
+
+fn evidence_oids() -> Result<Vec<(EvidenceExtension, Vec<u8>)>, RaTlsError> {
+    Ok(EvidenceExtension::ALL
+        .iter()
+        .map(|ext| ext.oid().as_raw().map(|oid| (*ext, oid)))
+        .collect::<Result<Vec<_>, _>>()?)
+}
 
-        self.token_verifier.verify(raw_token).inspect_err(|_| {error!("Token verification failed");})
+// Only the legacy extension may hold evidence that is not a DER encoded CMW
+fn unwrap_evidence(extension: EvidenceExtension, data: &[u8]) -> Result<Evidence, RaTlsError> {
+    match extension {
+        EvidenceExtension::Legacy => Ok(cmw::unwrap(data)),
+        EvidenceExtension::TcgDice | EvidenceExtension::Ietf => cmw::unwrap_der(data)
+            .inspect_err(|_| error!("Evidence extension does not hold a CMW"))
     }
 }
 
+fn fetch_inclusion_proof(cert: &X509Certificate) -> Result<Option<InclusionProof>, RaTlsError> {
+    let proof_oid = BATCH_PROOF_X509_EXT.as_raw()?;
+
+    cert.iter_extensions()
+        .find(|ext| ext.id.0.as_ref() == proof_oid.as_slice())
+        .map(|ext| InclusionProof::decode(ext.value.as_slice().ok_or(RaTlsError::InvalidInclusionProof)?))
+        .transpose()
+}
+
+// Older peers don't send the key origin
+fn fetch_key_origin(cert: &X509Certificate) -> Result<Option<KeyOrigin>, RaTlsError> {
+    let origin_oid = KEY_ORIGIN_X509_EXT.as_raw()?;
+
+    cert.iter_extensions()
+        .find(|ext| ext.id.0.as_ref() == origin_oid.as_slice())
+        .map(|ext| KeyOrigin::decode(ext.value.as_slice().ok_or(RaTlsError::InvalidKeyOrigin)?))
+        .transpose()
+}
+
+// The token of a certificate that was not verified, for the channel binding
+pub(crate) fn embedded_token(cert_der: &CertificateDer) -> Option<Vec<u8>> {
+    let cert = X509Certificate::from_der(cert_der).ok()?;
+    let evidence_oids = evidence_oids().ok()?;
+
+    let token = cert.iter_extensions().find_map(|ext| {
+        let (extension, _) = evidence_oids.iter().find(|(_, oid)| ext.id.0.as_ref() == oid.as_slice())?;
+        unwrap_evidence(*extension, ext.value.as_slice()?).ok()
+    });
+    token.map(|evidence| evidence.value)
+}
+
+fn carries_evidence(cert_der: &CertificateDer) -> Result<bool, RaTlsError> {
+    let cert = X509Certificate::from_der(cert_der)?;
+    let evidence_oids = evidence_oids()?;
+
+    let found = cert.iter_extensions().any(|ext| evidence_oids.iter().any(|(_, oid)| ext.id.0.as_ref() == oid.as_slice()));
+
+    Ok(found)
+}
+
 impl ClientCertVerifier for RaTlsCertVeryfier {
     fn verify_client_cert(
             &self,
             end_entity: &CertificateDer<'_>,
-            _intermediates: &[CertificateDer<'_>],
-            _now: UnixTime,
+            intermediates: &[CertificateDer<'_>],
+            now: UnixTime,
         ) -> Result<ClientCertVerified, rustls::Error> {
-        match self.verify_cert(end_entity) {
+        if let Some(client_cert_verifier) = &self.client_cert_verifier {
+            if !carries_evidence(end_entity).unwrap_or(false) {
+                return client_cert_verifier.verify_client_cert(end_entity, intermediates, now);
+            }
+        }
+
+        let verified = self.verify_chain(end_entity, intermediates, now, KeyUsage::client_auth())
+            .and_then(|_| self.verify_cert(end_entity));
+        match verified {
             Ok(()) => Ok(ClientCertVerified::assertion()),
-            Err(err) => Err(Error::InvalidCertificate(rustls::CertificateError::Other(rustls::OtherError(Arc::new(err)))))
+            Err(err) => Err(self.reject(err))
         }
     }
 
@@ -187,15 +361,59 @@ impl ServerCertVerifier for RaTlsCertVeryfier {
     fn verify_server_cert(
             &self,
             end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName,
+            _ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verify_server_name(end_entity, server_name)
+            .and_then(|_| self.verify_chain(end_entity, intermediates, now, KeyUsage::server_auth()))
+            .and_then(|_| self.verify_cert(end_entity));
+        match verified {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(err) => Err(self.reject(err))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &SUPPORTED_SIG_ALGS)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &SUPPORTED_SIG_ALGS)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        SUPPORTED_SIG_SCHEMES.to_vec()
+    }
+}
+
+// With post-handshake attestation the server certificate is not trusted by
+// itself, the server is authenticated by evidence bound to the TLS exporter.
+// Only the handshake signatures are checked here.
+#[derive(Debug)]
+pub(crate) struct PostHandshakeServerVerifier;
+
+impl ServerCertVerifier for PostHandshakeServerVerifier {
+    fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
             _intermediates: &[CertificateDer<'_>],
             _server_name: &ServerName,
             _ocsp_response: &[u8],
             _now: UnixTime,
         ) -> Result<ServerCertVerified, rustls::Error> {
-        match self.verify_cert(end_entity) {
-            Ok(()) => Ok(ServerCertVerified::assertion()),
-            Err(err) => Err(Error::InvalidCertificate(rustls::CertificateError::Other(rustls::OtherError(Arc::new(err)))))
-        }
+        Ok(ServerCertVerified::assertion())
     }
 
     fn verify_tls12_signature(
@@ -220,3 +438,200 @@ impl ServerCertVerifier for RaTlsCertVeryfier {
         SUPPORTED_SIG_SCHEMES.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rustls::{ClientConnection, ServerConnection};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use crate::{client::make_server_name, token_verifier::SkipVerification};
+    use crate::{CertificateIssuer, CertificateProfile, RaTlsClientBuilder, RaTlsServerBuilder, SampleEvidenceResolver, SampleEvidenceVerifier};
+    use super::*;
+
+    #[derive(Debug)]
+    struct RejectAll;
+
+    impl InternalTokenVerifier for RejectAll {
+        fn verify(&self, _token: &[u8]) -> Result<(), RaTlsError> {
+            Err(RaTlsError::TokenRejected("unknown measurement".to_owned()))
+        }
+    }
+
+    // Same choice as rustls makes when a certificate verifier fails
+    fn sent_alert(err: Error) -> AlertDescription {
+        match err {
+            Error::InvalidCertificate(err) => err.into(),
+            Error::PeerMisbehaved(_) => AlertDescription::IllegalParameter,
+            _ => AlertDescription::HandshakeFailure
+        }
+    }
+
+    #[test]
+    fn reported_alert_is_the_sent_one() {
+        let verifier = RaTlsCertVeryfier::from_token_verifier(Arc::new(SkipVerification));
+        let errors = [
+            RaTlsError::MissingTokenInCertificate,
+            RaTlsError::InvalidChallenge,
+            RaTlsError::InvalidCmw,
+            RaTlsError::TokenRejected("unknown measurement".to_owned()),
+            RaTlsError::GenericTokenVerifierError("connection refused".into()),
+            RaTlsError::ServerBusy
+        ];
+
+        for err in errors {
+            let report = AttestationReport::from(&err);
+            assert_eq!(sent_alert(verifier.reject(err)), report.alert(), "{}", report);
+        }
+    }
+
+    #[test]
+    fn generic_verifier_errors_are_neither_verdicts_nor_outages() {
+        let report = AttestationReport::from(&RaTlsError::GenericTokenVerifierError("RIM not allowed".into()));
+        assert_eq!(report.code(), "verifier-error");
+
+        let report = AttestationReport::from(&RaTlsError::VerifierUnavailable("connection refused".into()));
+        assert_eq!(report.code(), "verifier-unavailable");
+
+        let report = AttestationReport::from(&RaTlsError::TokenRejected("unknown measurement".to_owned()));
+        assert_eq!(report.code(), "policy-rejected");
+    }
+
+    // Runs the handshake in memory. A refused server gives the report of the
+    // client and the alert the server received.
+    fn handshake(
+        server: RaTlsServerBuilder,
+        client: RaTlsClientBuilder,
+        hostname: &str
+    ) -> Result<(), (AttestationReport, AlertDescription)> {
+        let (mut server_config, _) = server.build_config_factory().unwrap().make_config();
+        let (mut client_config, verifier) = client.build_config_factory().unwrap().make_config().unwrap();
+        // Only the certificates are under test, not the protocol names
+        server_config.alpn_protocols.clear();
+        client_config.alpn_protocols.clear();
+
+        let name = make_server_name(verifier.as_deref(), hostname.to_owned()).unwrap();
+        let mut client = ClientConnection::new(Arc::new(client_config), name).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+        for _ in 0..4 {
+            let mut records = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut records).unwrap();
+            }
+            let mut records = records.as_slice();
+            while !records.is_empty() {
+                server.read_tls(&mut records).unwrap();
+                match server.process_new_packets() {
+                    Err(Error::AlertReceived(alert)) => return Err((verifier.unwrap().failure().unwrap(), alert)),
+                    Err(err) => panic!("The server refused the client: {}", err),
+                    Ok(_) => ()
+                }
+            }
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+
+            let mut records = Vec::new();
+            while server.wants_write() {
+                server.write_tls(&mut records).unwrap();
+            }
+            let mut records = records.as_slice();
+            while !records.is_empty() {
+                client.read_tls(&mut records).unwrap();
+                let _ = client.process_new_packets();
+            }
+        }
+        panic!("The handshake did not finish");
+    }
+
+    fn refuse_server(server: RaTlsServerBuilder, evidence_verifiers: EvidenceVerifiers) -> (AttestationReport, AlertDescription) {
+        let client = RaTlsClientBuilder::new()
+            .with_server_attestation(Arc::new(RejectAll))
+            .with_evidence_verifiers(evidence_verifiers);
+        handshake(server, client, "localhost").unwrap_err()
+    }
+
+    #[test]
+    fn refused_server_receives_the_reported_alert() {
+        let resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let trusted = EvidenceVerifiers::new().with_verifier(Arc::new(SampleEvidenceVerifier::new(vec![resolver.public_key().to_vec()])));
+
+        let rcgen::CertifiedKey { cert, signing_key } = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(signing_key.serialize_der()).into();
+        let (report, alert) = refuse_server(RaTlsServerBuilder::new().with_certificate(vec![cert.der().clone()], key), trusted.clone());
+        assert_eq!(report.code(), "missing-evidence");
+        assert_eq!((report.alert(), alert), (AlertDescription::BadCertificate, AlertDescription::BadCertificate));
+
+        let (report, alert) = refuse_server(RaTlsServerBuilder::new().with_attestation(resolver), trusted);
+        assert_eq!(report.code(), "policy-rejected");
+        assert_eq!((report.alert(), alert), (AlertDescription::CertificateUnknown, AlertDescription::CertificateUnknown));
+    }
+
+    fn test_ca() -> (rcgen::Certificate, rcgen::KeyPair) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        (params.self_signed(&key).unwrap(), key)
+    }
+
+    #[test]
+    fn hybrid_verification_checks_chain_hostname_and_token() {
+        let resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let (ca, ca_key) = test_ca();
+        let (other_ca, _) = test_ca();
+        let issuer = || {
+            let key = PrivatePkcs8KeyDer::from(ca_key.serialize_der()).into();
+            CertificateIssuer::new(vec![ca.der().clone()], key).unwrap()
+        };
+        let server = || RaTlsServerBuilder::new()
+            .with_attestation(resolver.clone())
+            .with_certificate_issuer(issuer())
+            .with_certificate_profile(CertificateProfile::new().with_dns_name("server.example"));
+        let client = |root: &rcgen::Certificate, trusted_key: &[u8]| {
+            let mut root_store = RootCertStore::empty();
+            root_store.add(root.der().clone()).unwrap();
+            RaTlsClientBuilder::new()
+                .with_server_attestation(Arc::new(SkipVerification))
+                .with_evidence_verifiers(EvidenceVerifiers::new().with_verifier(Arc::new(SampleEvidenceVerifier::new(vec![trusted_key.to_vec()]))))
+                .with_root_certificates(root_store)
+                .with_hybrid_server_verification(true)
+        };
+        let refused = |client, hostname| handshake(server(), client, hostname).unwrap_err().0.code();
+
+        assert!(handshake(server(), client(&ca, resolver.public_key()), "server.example").is_ok());
+        assert_eq!(refused(client(&other_ca, resolver.public_key()), "server.example"), "invalid-certificate");
+        assert_eq!(refused(client(&ca, resolver.public_key()), "other.example"), "server-name-mismatch");
+        assert_eq!(refused(client(&ca, &[0; 32]), "server.example"), "untrusted-evidence-key");
+    }
+
+    #[test]
+    fn one_server_accepts_issued_and_attested_clients() {
+        let server_resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let client_resolver = Arc::new(SampleEvidenceResolver::generate(vec![1; 32]).unwrap());
+        let (ca, ca_key) = test_ca();
+        let mut root_store = RootCertStore::empty();
+        root_store.add(ca.der().clone()).unwrap();
+
+        let verifier = |key: &[u8]| EvidenceVerifiers::new().with_verifier(Arc::new(SampleEvidenceVerifier::new(vec![key.to_vec()])));
+        let server = || RaTlsServerBuilder::new()
+            .with_attestation(server_resolver.clone())
+            .with_client_attestation(Arc::new(SkipVerification))
+            .with_evidence_verifiers(verifier(client_resolver.public_key()))
+            .with_client_root_certificates(root_store.clone());
+        let client = || RaTlsClientBuilder::new()
+            .with_server_attestation(Arc::new(SkipVerification))
+            .with_evidence_verifiers(verifier(server_resolver.public_key()));
+
+        let issuer = CertificateIssuer::new(vec![ca.der().clone()], PrivatePkcs8KeyDer::from(ca_key.serialize_der()).into()).unwrap();
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec!["client.example".to_owned()]).unwrap();
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let issued = client().with_client_certificate(
+            issuer.issue(&params, &client_key).unwrap(),
+            PrivatePkcs8KeyDer::from(client_key.serialize_der()).into()
+        );
+        assert!(handshake(server(), issued, "localhost").is_ok());
+
+        let attested = client().with_attestation(client_resolver.clone());
+        assert!(handshake(server(), attested, "localhost").is_ok());
+    }
+}
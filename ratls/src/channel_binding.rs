@@ -0,0 +1,161 @@
+use std::sync::OnceLock;
+use log::error;
+use rustls::{ConnectionCommon, ProtocolVersion, SideData};
+use sha2::{Digest, Sha256};
+use crate::{attestation::PeerAttestation, cert_verifier::embedded_token, error::RaTlsError, post_handshake::Side};
+
+// RFC 9266 tls-exporter channel binding
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-Channel-Binding";
+
+// Raw tokens that went over a single connection, kept for the channel binding
+#[derive(Debug, Default)]
+pub(crate) struct ExchangedTokens {
+    pub(crate) local: OnceLock<Vec<u8>>,
+    // Evidence of the peer that was received but not verified, verified
+    // tokens are taken from the PeerAttestation
+    pub(crate) peer: OnceLock<Vec<u8>>
+}
+
+// Ties application messages to the attested channel. Both ends of the
+// connection get the same value: the exporter and the digests of the client
+// and the server tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelBinding {
+    tls_exporter: [u8; 32],
+    client_token_digest: Option<[u8; 32]>,
+    server_token_digest: Option<[u8; 32]>
+}
+
+impl ChannelBinding {
+    pub(crate) fn from_connection<S: SideData>(
+        conn: &ConnectionCommon<S>,
+        side: Side,
+        tokens: &ExchangedTokens,
+        peer_attestation: Option<&PeerAttestation>
+    ) -> Result<Self, RaTlsError> {
+        // RFC 9266 allows TLS 1.2 only with the extended master secret, which
+        // rustls does not report
+        if conn.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            error!("No tls-exporter channel binding for a {:?} connection", conn.protocol_version());
+            return Err(RaTlsError::UnsupportedProtocolVersion);
+        }
+        let tls_exporter = conn.export_keying_material([0u8; 32], EXPORTER_LABEL, Some(&[]))?;
+        let local_digest = tokens.local.get().map(|token| token_digest(token));
+        // An unverified peer still sent its token, either in the certificate
+        // or after the handshake
+        let peer_digest = match peer_attestation {
+            Some(attestation) => Some(token_digest(attestation.raw_token())),
+            None => tokens.peer
+                .get()
+                .cloned()
+                .or_else(|| conn.peer_certificates().and_then(|certs| certs.first()).and_then(embedded_token))
+                .map(|token| token_digest(&token))
+        };
+
+        let (client_token_digest, server_token_digest) = match side {
+            Side::Client => (local_digest, peer_digest),
+            Side::Server => (peer_digest, local_digest)
+        };
+
+        Ok(Self { tls_exporter, client_token_digest, server_token_digest })
+    }
+
+    pub fn tls_exporter(&self) -> &[u8] {
+        &self.tls_exporter
+    }
+
+    // SHA-256 of the raw attestation token, None if the client was not attested.
+    pub fn client_token_digest(&self) -> Option<&[u8]> {
+        self.client_token_digest.as_ref().map(|digest| digest.as_slice())
+    }
+
+    // SHA-256 of the raw attestation token, None if the server was not attested.
+    pub fn server_token_digest(&self) -> Option<&[u8]> {
+        self.server_token_digest.as_ref().map(|digest| digest.as_slice())
+    }
+
+    // The exporter followed by the client and the server token digests, a side
+    // that was not attested is 32 zero bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.tls_exporter.to_vec();
+        for digest in [&self.client_token_digest, &self.server_token_digest] {
+            bytes.extend_from_slice(digest.as_ref().unwrap_or(&[0u8; 32]));
+        }
+        bytes
+    }
+}
+
+fn token_digest(token: &[u8]) -> [u8; 32] {
+    Sha256::digest(token).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use rcgen::CertifiedKey;
+    use rustls::{crypto::ring::default_provider, pki_types::PrivatePkcs8KeyDer, version::{TLS12, TLS13}};
+    use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, SupportedProtocolVersion};
+    use crate::cert_verifier::PostHandshakeServerVerifier;
+    use super::*;
+
+    fn connect(version: &'static SupportedProtocolVersion) -> (ClientConnection, ServerConnection) {
+        let CertifiedKey { cert, signing_key } = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], PrivatePkcs8KeyDer::from(signing_key.serialize_der()).into())
+            .unwrap();
+        let client_config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PostHandshakeServerVerifier))
+            .with_no_client_auth();
+
+        let mut client = ClientConnection::new(Arc::new(client_config), "localhost".try_into().unwrap()).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        while client.is_handshaking() || server.is_handshaking() {
+            transfer(&mut client, &mut server);
+            transfer(&mut server, &mut client);
+        }
+        (client, server)
+    }
+
+    fn transfer<A: SideData, B: SideData>(from: &mut ConnectionCommon<A>, to: &mut ConnectionCommon<B>) {
+        let mut records = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut records).unwrap();
+        }
+        let mut records = records.as_slice();
+        while !records.is_empty() {
+            to.read_tls(&mut records).unwrap();
+            to.process_new_packets().unwrap();
+        }
+    }
+
+    fn bindings(client: &ClientConnection, server: &ServerConnection) -> [Result<ChannelBinding, RaTlsError>; 2] {
+        let tokens = ExchangedTokens::default();
+        [
+            ChannelBinding::from_connection(client, Side::Client, &tokens, None),
+            ChannelBinding::from_connection(server, Side::Server, &tokens, None)
+        ]
+    }
+
+    #[test]
+    fn tls13_connections_are_bound() {
+        let (client, server) = connect(&TLS13);
+        let [client, server] = bindings(&client, &server);
+
+        assert_eq!(client.unwrap(), server.unwrap());
+    }
+
+    #[test]
+    fn tls12_connections_are_refused() {
+        let (client, server) = connect(&TLS12);
+
+        for binding in bindings(&client, &server) {
+            assert!(matches!(binding, Err(RaTlsError::UnsupportedProtocolVersion)));
+        }
+    }
+}
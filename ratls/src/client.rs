@@ -1,8 +1,12 @@
 use std::{net::TcpStream, sync::Arc};
-use rustls::{pki_types::{DnsName, ServerName}, ClientConfig, ClientConnection};
-use crate::{cert_resolver::RaTlsCertResolver, cert_verifier::RaTlsCertVeryfier,
-    error::RaTlsError, tools::{self, load_certificates_from_pem, load_private_key_from_file, load_root_cert_store}};
-use crate::connection::RaTlsConnection;
+use rustls::{pki_types::ServerName, sign::{CertifiedKey, SingleCertAndKey}, version::TLS13, ClientConfig, ClientConnection, RootCertStore};
+use crate::{builder::RaTlsClientBuilder, cert_resolver::{RaTlsCertResolver, ResolverOptions}, cert_verifier::{PostHandshakeServerVerifier, RaTlsCertVeryfier}, error::RaTlsError};
+use crate::{config::{RaTlsProtocol, HANDSHAKE_MSG}, connection::RaTlsConnection};
+use crate::channel_binding::ExchangedTokens;
+use crate::evidence::EvidenceVerifiers;
+use crate::key_pool::KeyPool;
+use crate::post_handshake::{PostHandshake, Side};
+use crate::sni::encode_server_name;
 use crate::token_resolver::InternalTokenResolver;
 use crate::token_verifier::InternalTokenVerifier;
 use std::io::{Read, Write};
@@ -23,67 +27,157 @@ pub enum ClientMode {
     }
 }
 
-pub struct RaTlsClient {
-    mode: ClientMode
+#[derive(Debug, Clone)]
+pub(crate) enum ClientCredentials {
+    Certificate(Arc<CertifiedKey>),
+    // The key is generated once and reused by all the connections
+    Attestation(Arc<RaTlsCertResolver>),
+    // Every connection takes a fresh key from the pool
+    AttestationWithKeyPool(Arc<dyn InternalTokenResolver>, ResolverOptions, Arc<KeyPool>)
 }
 
-impl RaTlsClient {
-    pub fn new(mode: ClientMode) -> Result<Self, RaTlsError> {
-        Ok(Self { mode })
+// A config for a single connection and the state it shares with the connection
+pub(crate) type ConnectionConfig = (ClientConfig, Option<Arc<RaTlsCertVeryfier>>, Arc<ExchangedTokens>);
+
+#[derive(Debug, Clone)]
+pub(crate) enum ServerVerification {
+    RootCertificates(Arc<RootCertStore>),
+    // With root certificates the chain is checked as well as the evidence
+    Attestation(Arc<dyn InternalTokenVerifier>, Arc<EvidenceVerifiers>, Option<Arc<RootCertStore>>),
+    // The server is attested after the handshake
+    PostHandshake
+}
+
+// Client side counterpart of ServerConfigFactory. In attested server mode
+// the verifier holds the challenge so a new config is needed per connection.
+#[derive(Debug, Clone)]
+pub struct ClientConfigFactory {
+    client_credentials: Option<ClientCredentials>,
+    server_verification: ServerVerification,
+    post_handshake: Option<PostHandshake>,
+    protocol: RaTlsProtocol
+}
+
+impl ClientConfigFactory {
+    pub(crate) fn new(
+        client_credentials: Option<ClientCredentials>,
+        server_verification: ServerVerification,
+        post_handshake: Option<PostHandshake>,
+        protocol: RaTlsProtocol
+    ) -> Self {
+        Self { client_credentials, server_verification, post_handshake, protocol }
+    }
+
+    pub(crate) fn protocol(&self) -> &RaTlsProtocol {
+        &self.protocol
+    }
+
+    // Set if the evidence is exchanged after the TLS handshake
+    pub(crate) fn post_handshake(&self) -> Option<&PostHandshake> {
+        self.post_handshake.as_ref()
+    }
+
+    // The returned config must be used for a single connection only, the
+    // verifier gives access to the server attestation once it is verified.
+    pub fn make_config(&self) -> Result<(ClientConfig, Option<Arc<RaTlsCertVeryfier>>), RaTlsError> {
+        let (config, verifier, _) = self.config_for_connection()?;
+        Ok((config, verifier))
     }
 
-    fn make_client_config(&self) -> Result<(ClientConfig, Option<String>), RaTlsError> {
-        tools::install_default_crypto_provider();
-        match &self.mode {
-            ClientMode::AttestedClient { client_token_resolver, root_ca_path } => {
-                Ok((ClientConfig::builder()
-                        .with_root_certificates(load_root_cert_store(root_ca_path)?)
-                        .with_client_cert_resolver(Arc::new(RaTlsCertResolver::from_token_resolver(client_token_resolver.clone())?)),
-                    None
-                ))
+    // The tokens are recorded for the channel binding of the connection
+    pub(crate) fn config_for_connection(&self) -> Result<ConnectionConfig, RaTlsError> {
+        let tokens = Arc::new(ExchangedTokens::default());
+        // The evidence is bound to the exporter, see ServerConfigFactory
+        let builder = match self.post_handshake {
+            Some(_) => ClientConfig::builder_with_protocol_versions(&[&TLS13]),
+            None => ClientConfig::builder()
+        };
+        let (builder, verifier) = match &self.server_verification {
+            ServerVerification::RootCertificates(root_store) => {
+                (builder.with_root_certificates(root_store.clone()), None)
             },
-            ClientMode::AttestedServer { client_certificate_path, client_privatekey_path, server_token_verifier } => {
-                let verifier = RaTlsCertVeryfier::from_token_verifier(server_token_verifier.clone());
-                let chall = verifier.b64_challenge();
-                Ok((ClientConfig::builder()
-                    .dangerous()
-                    .with_custom_certificate_verifier(Arc::new(verifier))
-                    .with_client_auth_cert(
-                        load_certificates_from_pem(client_certificate_path)?,
-                        load_private_key_from_file(client_privatekey_path)?
-                    )?,
-                    Some(chall)
-                ))
+            ServerVerification::Attestation(server_token_verifier, evidence_verifiers, root_store) => {
+                let verifier = RaTlsCertVeryfier::with_evidence_verifiers(server_token_verifier.clone(), evidence_verifiers.clone());
+                let verifier = Arc::new(match root_store {
+                    Some(root_store) => verifier.with_chain_anchors(root_store.clone()),
+                    None => verifier
+                });
+                (builder.dangerous().with_custom_certificate_verifier(verifier.clone()), Some(verifier))
             },
-            ClientMode::MutualAttestation { client_token_resolver, server_token_verifier } => {
-                let verifier = RaTlsCertVeryfier::from_token_verifier(server_token_verifier.clone());
-                let chall = verifier.b64_challenge();
-                Ok((ClientConfig::builder()
-                    .dangerous()
-                    .with_custom_certificate_verifier(Arc::new(verifier))
-                    .with_client_cert_resolver(Arc::new(RaTlsCertResolver::from_token_resolver(client_token_resolver.clone())?)),
-                    Some(chall)
-                ))
+            ServerVerification::PostHandshake => {
+                (builder.dangerous().with_custom_certificate_verifier(Arc::new(PostHandshakeServerVerifier)), None)
             }
-        }
+        };
+
+        let mut config = match &self.client_credentials {
+            None => builder.with_no_client_auth(),
+            Some(ClientCredentials::Certificate(certified_key)) => {
+                builder.with_client_cert_resolver(Arc::new(SingleCertAndKey::from(certified_key.clone())))
+            },
+            Some(ClientCredentials::Attestation(cert_resolver)) => {
+                builder.with_client_cert_resolver(cert_resolver.for_connection(None, tokens.clone()))
+            },
+            Some(ClientCredentials::AttestationWithKeyPool(client_token_resolver, resolver_options, key_pool)) => {
+                let cert_resolver = Arc::new(RaTlsCertResolver::with_options_and_key(
+                    client_token_resolver.clone(),
+                    resolver_options.clone(),
+                    key_pool.take()?
+                )?);
+                builder.with_client_cert_resolver(cert_resolver.for_connection(None, tokens.clone()))
+            }
+        };
+
+        // Applications may add their own protocols after this one
+        config.alpn_protocols = self.protocol.alpn_protocols();
+
+        Ok((config, verifier, tokens))
+    }
+}
+
+// In attested server mode the SNI carries the challenge for the server.
+pub(crate) fn make_server_name(verifier: Option<&RaTlsCertVeryfier>, server_name: String) -> Result<ServerName<'static>, RaTlsError> {
+    Ok(match verifier {
+        Some(verifier) => ServerName::DnsName(encode_server_name(verifier.challenge(), &server_name)?),
+        None => ServerName::try_from(server_name)?
+    })
+}
+
+pub struct RaTlsClient {
+    config_factory: ClientConfigFactory
+}
+
+impl RaTlsClient {
+    pub fn new(mode: ClientMode) -> Result<Self, RaTlsError> {
+        RaTlsClientBuilder::from_mode(mode)?.build()
+    }
+
+    pub fn from_config_factory(config_factory: ClientConfigFactory) -> Self {
+        Self { config_factory }
     }
 
     pub fn connect(&self, server_url: String, server_name: String) -> Result<RaTlsConnection<ClientConnection>, RaTlsError> {
         let sock = TcpStream::connect(server_url)?;
-        let (config, challenge) = self.make_client_config()?;
+        let (config, verifier, tokens) = self.config_factory.config_for_connection()?;
         let conn = ClientConnection::new(
             Arc::new(config),
-            ServerName::DnsName(DnsName::try_from(challenge.unwrap_or(server_name))?)
+            make_server_name(verifier.as_deref(), server_name)?
         )?;
 
-        let mut tlsconn = RaTlsConnection::new(sock, conn);
-        self.handshake(&mut tlsconn)?;
+        let mut tlsconn = RaTlsConnection::with_verifier(sock, conn, verifier, tokens);
+        tlsconn.complete_handshake(None)?;
+        let legacy_handshake = self.config_factory.protocol().check(tlsconn.connection().alpn_protocol())?;
+
+        match self.config_factory.post_handshake() {
+            Some(post_handshake) => post_handshake.exchange(&mut tlsconn, Side::Client, None)?,
+            None if legacy_handshake => self.handshake(&mut tlsconn)?,
+            None => ()
+        }
         Ok(tlsconn)
     }
 
     fn handshake(&self, conn: &mut RaTlsConnection<ClientConnection>) -> Result<(), RaTlsError> {
         let mut stream = conn.stream();
-        let msg = "HELO";
+        let msg = HANDSHAKE_MSG;
 
         let mut resp = vec![0; msg.len()];
         stream.read_exact(&mut resp)?;
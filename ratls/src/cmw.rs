@@ -0,0 +1,128 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD as b64url};
+use ciborium::Value;
+use simple_asn1::ASN1Block;
+use crate::error::RaTlsError;
+
+// Media type of the CCA attestation token (platform and realm token collection)
+pub(crate) const CCA_MEDIA_TYPE: &str = "application/eat-collection; profile=arm-cca";
+
+// How the evidence is put into the certificate extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvidenceEncoding {
+    // Bare token, as done by the older versions of this crate
+    Raw,
+    // Conceptual Message Wrapper record: [type, value] in CBOR
+    #[default]
+    CmwCbor,
+    // Conceptual Message Wrapper record: ["type", "base64url(value)"] in JSON
+    CmwJson
+}
+
+#[derive(Debug)]
+pub(crate) struct Evidence {
+    // None for bare tokens which are assumed to be CCA
+    pub(crate) media_type: Option<String>,
+    pub(crate) value: Vec<u8>
+}
+
+// Records are DER encoded as in the CMW X.509 extension:
+// CMW ::= CHOICE { json UTF8String, cbor OCTET STRING }
+pub(crate) fn wrap(media_type: &str, value: Vec<u8>, encoding: EvidenceEncoding) -> Result<Vec<u8>, RaTlsError> {
+    match encoding {
+        EvidenceEncoding::Raw => Ok(value),
+        EvidenceEncoding::CmwCbor => {
+            let record = Value::Array(vec![Value::Text(media_type.to_owned()), Value::Bytes(value)]);
+            let mut encoded = Vec::new();
+            ciborium::into_writer(&record, &mut encoded)?;
+            Ok(simple_asn1::to_der(&ASN1Block::OctetString(0, encoded))?)
+        },
+        EvidenceEncoding::CmwJson => {
+            let record = serde_json::json!([media_type, b64url.encode(value)]);
+            Ok(simple_asn1::to_der(&ASN1Block::UTF8String(0, serde_json::to_string(&record)?))?)
+        }
+    }
+}
+
+// Evidence under the standard CMW extensions, has to be a DER encoded record.
+pub(crate) fn unwrap_der(data: &[u8]) -> Result<Evidence, RaTlsError> {
+    match simple_asn1::from_der(data)?.as_slice() {
+        [ASN1Block::OctetString(_, record)] => unwrap_cbor(record),
+        [ASN1Block::UTF8String(_, record)] => unwrap_json(record.as_bytes()),
+        _ => Err(RaTlsError::InvalidCmw)
+    }
+}
+
+// The legacy extension and the post-handshake frames may also carry plain
+// CBOR or JSON records, as put there by the older versions of this crate, or
+// bare tokens (e.g. CCA token starts with the CBOR tag 399). Bare evidence can be an array
+// too (e.g. the sample format), so arrays that are not valid records are
+// taken as bare evidence.
+pub(crate) fn unwrap(data: &[u8]) -> Evidence {
+    let record = match data.first() {
+        Some(0x04 | 0x0c) => unwrap_der(data),
+        Some(b'[') => unwrap_json(data),
+        Some(0x80..=0x9f) => unwrap_cbor(data),
+        _ => Err(RaTlsError::InvalidCmw)
+    };
+
+    record.unwrap_or_else(|_| Evidence { media_type: None, value: data.to_vec() })
+}
+
+fn unwrap_cbor(data: &[u8]) -> Result<Evidence, RaTlsError> {
+    let record: Value = ciborium::from_reader(data)?;
+
+    match record {
+        Value::Array(items) if matches!(items.len(), 2 | 3) => match (&items[0], &items[1]) {
+            (Value::Text(media_type), Value::Bytes(value)) => Ok(Evidence {
+                media_type: Some(media_type.clone()),
+                value: value.clone()
+            }),
+            // Numeric CoAP Content-Formats are not supported
+            _ => Err(RaTlsError::InvalidCmw)
+        },
+        _ => Err(RaTlsError::InvalidCmw)
+    }
+}
+
+fn unwrap_json(data: &[u8]) -> Result<Evidence, RaTlsError> {
+    let record: serde_json::Value = serde_json::from_slice(data)?;
+
+    match record.as_array().map(|items| items.as_slice()) {
+        Some([media_type, value]) | Some([media_type, value, _]) => match (media_type.as_str(), value.as_str()) {
+            (Some(media_type), Some(value)) => Ok(Evidence {
+                media_type: Some(media_type.to_owned()),
+                value: b64url.decode(value)?
+            }),
+            _ => Err(RaTlsError::InvalidCmw)
+        },
+        _ => Err(RaTlsError::InvalidCmw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_der_encoded_choices() {
+        let cbor = wrap(CCA_MEDIA_TYPE, vec![1, 2, 3], EvidenceEncoding::CmwCbor).unwrap();
+        let json = wrap(CCA_MEDIA_TYPE, vec![1, 2, 3], EvidenceEncoding::CmwJson).unwrap();
+        assert_eq!(cbor[0], 0x04);
+        assert_eq!(json[0], 0x0c);
+
+        for wrapped in [cbor, json] {
+            let evidence = unwrap_der(&wrapped).unwrap();
+            assert_eq!(evidence.media_type.as_deref(), Some(CCA_MEDIA_TYPE));
+            assert_eq!(evidence.value, [1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn plain_records_are_only_accepted_by_the_legacy_unwrap() {
+        let record = serde_json::to_vec(&serde_json::json!([CCA_MEDIA_TYPE, b64url.encode([1, 2, 3])])).unwrap();
+
+        assert!(matches!(unwrap_der(&record), Err(RaTlsError::Asn1DecodeError(_)) | Err(RaTlsError::InvalidCmw)));
+        assert_eq!(unwrap(&record).media_type.as_deref(), Some(CCA_MEDIA_TYPE));
+        assert!(unwrap(&[0xd9, 0x01, 0x8f]).media_type.is_none());
+    }
+}
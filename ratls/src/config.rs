@@ -1,7 +1,180 @@
 use lazy_static::lazy_static;
+use log::error;
 use simple_asn1::{OID, oid};
+use crate::{cmw::EvidenceEncoding, error::RaTlsError};
 
-// This is random, if you know better please change this XD
 lazy_static! {
-    pub(crate) static ref CCA_TOKEN_X509_EXT: OID = oid!(1, 3, 3, 3, 7);
+    // tcg-dice-conceptual-message-wrapper, formerly tcg-dice-TaggedEvidence
+    pub(crate) static ref TCG_DICE_CMW_X509_EXT: OID = oid!(2, 23, 133, 5, 4, 9);
+    // id-pe-cmw from the IETF RATS conceptual message wrapper draft
+    pub(crate) static ref IETF_CMW_X509_EXT: OID = oid!(1, 3, 6, 1, 5, 5, 7, 1, 35);
+    // Placeholder used by the older versions of this crate, still accepted
+    // by the verifier until all the peers are migrated.
+    pub(crate) static ref LEGACY_CCA_TOKEN_X509_EXT: OID = oid!(1, 3, 3, 3, 7);
+    // Inclusion proof of the challenge for evidence shared by a batch of
+    // handshakes, private to this crate
+    pub(crate) static ref BATCH_PROOF_X509_EXT: OID = oid!(1, 3, 3, 3, 8);
+    // Where the key of the certificate comes from, private to this crate
+    pub(crate) static ref KEY_ORIGIN_X509_EXT: OID = oid!(1, 3, 3, 3, 9);
+}
+
+// X.509 extension used to carry the evidence in the certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvidenceExtension {
+    #[default]
+    TcgDice,
+    Ietf,
+    Legacy
+}
+
+impl EvidenceExtension {
+    pub(crate) const ALL: [EvidenceExtension; 3] = [Self::TcgDice, Self::Ietf, Self::Legacy];
+
+    pub(crate) fn oid(&self) -> &'static OID {
+        match self {
+            Self::TcgDice => &TCG_DICE_CMW_X509_EXT,
+            Self::Ietf => &IETF_CMW_X509_EXT,
+            Self::Legacy => &LEGACY_CCA_TOKEN_X509_EXT
+        }
+    }
+
+    // The standard extensions hold a CMW, a bare token is allowed only in the
+    // legacy one.
+    pub(crate) fn check_encoding(&self, encoding: EvidenceEncoding) -> Result<(), RaTlsError> {
+        match (self, encoding) {
+            (Self::TcgDice | Self::Ietf, EvidenceEncoding::Raw) => Err(RaTlsError::RawEvidenceInCmwExtension),
+            _ => Ok(())
+        }
+    }
+}
+
+// How the challenge and the evidence travel in the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AttestationTransport {
+    // Evidence in a certificate extension, challenge in the SNI or CA hints
+    #[default]
+    X509Extension,
+    // Regular TLS handshake followed by an exchange of evidence bound to the
+    // session with the TLS exporter, works through TLS terminators too.
+    PostHandshake
+}
+
+impl AttestationTransport {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::X509Extension => "x509",
+            Self::PostHandshake => "post-handshake"
+        }
+    }
+}
+
+// Marks the challenge among the acceptable issuers of a CertificateRequest,
+// the rest of them are regular CA distinguished names.
+pub(crate) const CHALLENGE_HINT_PREFIX: &[u8] = b"ratls-nonce:";
+
+// Exchanged by both sides right after the TLS handshake with peers that
+// don't negotiate the ratls protocol with ALPN
+pub(crate) const HANDSHAKE_MSG: &str = "HELO";
+
+const ALPN_PROTOCOL_VERSION: &str = "ratls/1";
+
+// Evidence family of a side that isn't attested
+const NO_EVIDENCE: &str = "none";
+
+// Short name of the evidence format: the profile of the media type when it
+// has one, its subtype otherwise, e.g. "arm-cca" or "ratls.sample-evidence".
+pub(crate) fn evidence_family(media_type: &str) -> String {
+    let mut parts = media_type.split(';').map(str::trim);
+    let essence = parts.next().unwrap_or_default();
+    let family = match parts.find_map(|param| param.strip_prefix("profile=")) {
+        Some(profile) => profile.trim_matches('"'),
+        None => {
+            let subtype = essence.rsplit('/').next().unwrap_or(essence);
+            let subtype = subtype.split('+').next().unwrap_or(subtype);
+            subtype.strip_prefix("vnd.").unwrap_or(subtype)
+        }
+    };
+    family.to_ascii_lowercase().replace('+', "-")
+}
+
+// ratls protocols negotiated with ALPN, named
+// "ratls/1+<transport>+<server evidence>+<client evidence>", e.g.
+// "ratls/1+x509+arm-cca+none". Both sides have to agree on the transport
+// and on the evidence families, the encoding of the evidence is free.
+#[derive(Debug, Clone)]
+pub(crate) struct RaTlsProtocol {
+    // One per combination of evidence families, in order of preference
+    alpn_protocols: Vec<Vec<u8>>,
+    // Fall back to HELO with peers not using ALPN
+    legacy_handshake: bool
+}
+
+impl RaTlsProtocol {
+    // Media types of the evidence each side may present, none for a side
+    // that isn't attested.
+    pub(crate) fn new(
+        transport: AttestationTransport,
+        server_evidence: &[&str],
+        client_evidence: &[&str],
+        legacy_handshake: bool
+    ) -> Self {
+        let families = |media_types: &[&str]| match media_types {
+            [] => vec![NO_EVIDENCE.to_owned()],
+            _ => media_types.iter().map(|media_type| evidence_family(media_type)).collect::<Vec<_>>()
+        };
+
+        let mut alpn_protocols = Vec::new();
+        for server_family in families(server_evidence) {
+            for client_family in families(client_evidence) {
+                let alpn_protocol = format!("{}+{}+{}+{}", ALPN_PROTOCOL_VERSION, transport.name(), server_family, client_family);
+                if !alpn_protocols.contains(&alpn_protocol.as_bytes().to_vec()) {
+                    alpn_protocols.push(alpn_protocol.into_bytes());
+                }
+            }
+        }
+
+        Self { alpn_protocols, legacy_handshake }
+    }
+
+    pub(crate) fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        self.alpn_protocols.clone()
+    }
+
+    // Returns whether the HELO messages have to be exchanged.
+    pub(crate) fn check(&self, negotiated: Option<&[u8]>) -> Result<bool, RaTlsError> {
+        match negotiated {
+            Some(protocol) if self.alpn_protocols.iter().any(|alpn_protocol| alpn_protocol == protocol) => Ok(false),
+            None if self.legacy_handshake => Ok(true),
+            _ => {
+                let offered = self.alpn_protocols.iter().map(|protocol| String::from_utf8_lossy(protocol)).collect::<Vec<_>>();
+                error!("Peer negotiated {:?} instead of one of {:?}", negotiated.map(String::from_utf8_lossy), offered);
+                Err(RaTlsError::ProtocolMismatch)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cmw::CCA_MEDIA_TYPE, sample_evidence::SAMPLE_MEDIA_TYPE};
+    use super::*;
+
+    #[test]
+    fn evidence_families_are_short_names_of_the_media_types() {
+        assert_eq!(evidence_family(CCA_MEDIA_TYPE), "arm-cca");
+        assert_eq!(evidence_family(SAMPLE_MEDIA_TYPE), "ratls.sample-evidence");
+        assert_eq!(evidence_family("application/eat+cwt; profile=\"tag:example.com,2024:x\""), "tag:example.com,2024:x");
+    }
+
+    #[test]
+    fn protocol_names_the_transport_and_both_evidence_families() {
+        let client = RaTlsProtocol::new(AttestationTransport::X509Extension, &[CCA_MEDIA_TYPE, SAMPLE_MEDIA_TYPE], &[], false);
+        assert_eq!(client.alpn_protocols(), vec![b"ratls/1+x509+arm-cca+none".to_vec(), b"ratls/1+x509+ratls.sample-evidence+none".to_vec()]);
+
+        let server = RaTlsProtocol::new(AttestationTransport::X509Extension, &[SAMPLE_MEDIA_TYPE], &[], false);
+        assert!(!client.check(Some(b"ratls/1+x509+ratls.sample-evidence+none")).unwrap());
+        assert!(server.check(Some(b"ratls/1+x509+arm-cca+none")).is_err());
+        assert!(server.check(Some(b"ratls/1+post-handshake+ratls.sample-evidence+none")).is_err());
+        assert!(server.check(None).is_err());
+    }
 }
@@ -1,18 +1,141 @@
-use std::{net::TcpStream, ops::DerefMut};
-use rustls::{Stream, ConnectionCommon, SideData};
+use std::{io::{self, Read, Write}, net::TcpStream, ops::DerefMut, sync::Arc, time::Instant};
+use rustls::{Stream, ClientConnection, ConnectionCommon, ServerConnection, SideData};
 use std::ops::Deref;
+use crate::{attestation::PeerAttestation, cert_verifier::RaTlsCertVeryfier, error::RaTlsError};
+use crate::channel_binding::{ChannelBinding, ExchangedTokens};
+use crate::post_handshake::Side;
+use crate::failure::with_report;
 
 pub struct RaTlsConnection<C> {
     sock: TcpStream,
     conn: C,
+    verifier: Option<Arc<RaTlsCertVeryfier>>,
+    // Set when the peer is attested after the handshake
+    attestation: Option<Arc<PeerAttestation>>,
+    tokens: Arc<ExchangedTokens>
 }
 
 impl<C: DerefMut + Deref<Target = ConnectionCommon<S>>, S: SideData> RaTlsConnection<C> {
     pub fn new(sock: TcpStream, conn: C) -> Self {
-        Self { sock, conn }
+        Self { sock, conn, verifier: None, attestation: None, tokens: Arc::default() }
+    }
+
+    pub(crate) fn with_verifier(
+        sock: TcpStream,
+        conn: C,
+        verifier: Option<Arc<RaTlsCertVeryfier>>,
+        tokens: Arc<ExchangedTokens>
+    ) -> Self {
+        Self { sock, conn, verifier, attestation: None, tokens }
     }
 
     pub fn stream<'a>(&'a mut self) -> Stream<'a, C, TcpStream> {
         Stream::new(&mut self.conn, &mut self.sock)
     }
+
+    pub(crate) fn stream_until(&mut self, deadline: Option<Instant>) -> DeadlineStream<'_, C> {
+        DeadlineStream { conn: self, deadline }
+    }
+
+    // None if the peer was not attested or the handshake is not finished yet.
+    pub fn peer_attestation(&self) -> Option<Arc<PeerAttestation>> {
+        self.attestation
+            .clone()
+            .or_else(|| self.verifier.as_ref().and_then(|verifier| verifier.peer_attestation()))
+    }
+
+    pub(crate) fn set_peer_attestation(&mut self, attestation: Arc<PeerAttestation>) {
+        self.attestation = Some(attestation);
+    }
+
+    pub(crate) fn tokens(&self) -> &ExchangedTokens {
+        &self.tokens
+    }
+
+    pub(crate) fn connection(&self) -> &C {
+        &self.conn
+    }
+
+    pub(crate) fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    fn limit_io(&self, deadline: Instant) -> io::Result<()> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        self.sock.set_read_timeout(Some(remaining))?;
+        self.sock.set_write_timeout(Some(remaining))?;
+        Ok(())
+    }
+
+    // With a deadline every socket operation is limited to the time left, so
+    // a peer trickling bytes cannot keep the handshake going forever.
+    pub(crate) fn complete_handshake(&mut self, deadline: Option<Instant>) -> Result<(), RaTlsError> {
+        while self.conn.is_handshaking() {
+            if let Some(deadline) = deadline {
+                self.limit_io(deadline)?;
+            }
+            self.conn
+                .complete_io(&mut self.sock)
+                .map_err(|err| match err.kind() {
+                    // Unix reports an expired socket timeout as WouldBlock
+                    io::ErrorKind::WouldBlock if deadline.is_some() => io::Error::from(io::ErrorKind::TimedOut),
+                    _ => err
+                })
+                .map_err(|err| with_report(err.into(), self.verifier.as_deref()))?;
+        }
+        Ok(())
+    }
+}
+
+// Every read and write is limited to the time left, so a peer sending a
+// frame byte by byte can't outlast the deadline.
+pub(crate) struct DeadlineStream<'a, C> {
+    conn: &'a mut RaTlsConnection<C>,
+    deadline: Option<Instant>
+}
+
+impl<C: DerefMut + Deref<Target = ConnectionCommon<S>>, S: SideData> DeadlineStream<'_, C> {
+    fn limited<T>(&mut self, io: impl FnOnce(&mut Stream<'_, C, TcpStream>) -> io::Result<T>) -> io::Result<T> {
+        let Some(deadline) = self.deadline else {
+            return io(&mut self.conn.stream());
+        };
+        self.conn.limit_io(deadline)?;
+        io(&mut self.conn.stream()).map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock => io::Error::from(io::ErrorKind::TimedOut),
+            _ => err
+        })
+    }
+}
+
+impl<C: DerefMut + Deref<Target = ConnectionCommon<S>>, S: SideData> Read for DeadlineStream<'_, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.limited(|stream| stream.read(buf))
+    }
+}
+
+impl<C: DerefMut + Deref<Target = ConnectionCommon<S>>, S: SideData> Write for DeadlineStream<'_, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.limited(|stream| stream.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.limited(|stream| stream.flush())
+    }
+}
+
+impl RaTlsConnection<ServerConnection> {
+    // Available once the handshake is finished, equal on both ends.
+    pub fn channel_binding(&self) -> Result<ChannelBinding, RaTlsError> {
+        ChannelBinding::from_connection(&self.conn, Side::Server, &self.tokens, self.peer_attestation().as_deref())
+    }
+}
+
+impl RaTlsConnection<ClientConnection> {
+    // Available once the handshake is finished, equal on both ends.
+    pub fn channel_binding(&self) -> Result<ChannelBinding, RaTlsError> {
+        ChannelBinding::from_connection(&self.conn, Side::Client, &self.tokens, self.peer_attestation().as_deref())
+    }
 }
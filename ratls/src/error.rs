@@ -5,6 +5,7 @@ use base64::DecodeError;
 use rust_rsi::TokenError;
 use rustls::pki_types::InvalidDnsNameError;
 use x509_certificate::X509CertificateError;
+use crate::failure::AttestationReport;
 
 #[derive(Debug)]
 pub enum RaTlsError {
@@ -29,9 +30,61 @@ pub enum RaTlsError {
     InvalidChallenge,
     HandshakeError,
     PkcsDERError(pkcs8::der::Error),
+    WebpkiError(webpki::Error),
+    VerifierBuilderError(rustls::server::VerifierBuilderError),
+    MissingServerCredentials,
+    MissingServerVerification,
+    // Hybrid server verification needs the root certificates
+    MissingRootCertificates,
+    CborDecodeError(ciborium::de::Error<std::io::Error>),
+    CborEncodeError(ciborium::ser::Error<std::io::Error>),
+    JsonError(serde_json::Error),
+    InvalidCmw,
+    // EvidenceEncoding::Raw was chosen for a standard CMW extension
+    RawEvidenceInCmwExtension,
+    UnsupportedEvidenceType(String),
+    InvalidServerName,
+    MissingPeerEvidence,
+    InvalidAttestationFrame,
+    ProtocolMismatch,
+    // The TLS exporter is bound to the connection only with TLS 1.3
+    UnsupportedProtocolVersion,
+    InvalidSampleEvidence,
+    UntrustedEvidenceKey,
+    InvalidEvidenceSignature,
+    AttestationFailed(AttestationReport),
+    // Too many handshakes in progress, the connection was closed
+    ServerBusy,
+    // The token rate limit or the token request queue was exceeded
+    RateLimited,
+    InvalidInclusionProof,
+    // The token shared by a batch of handshakes could not be resolved
+    BatchFailed,
+    KeyRotationFailed,
+    // Only generated keys of the certificate extension transport are rotated
+    KeyRotationUnsupported,
+    // Token batching applies to the certificate extension transport of the
+    // blocking server only
+    TokenBatchingUnsupported,
+    // The signing key has no scheme usable for the certificate or does not
+    // report its public key
+    UnsupportedSigningKey,
+    InvalidKeyOrigin,
+    // Extensions of this crate can't be set by the certificate profile
+    ReservedExtension(Vec<u64>),
+    InvalidValidity,
+    MissingIssuerCertificate,
+    // The issuer key does not belong to the first certificate of the chain
+    IssuerKeyMismatch,
+    // The claims policy could not be parsed
+    InvalidPolicy(String),
 
     GenericTokenResolverError(Box<dyn std::error::Error + Sync + Send>),
-    GenericTokenVerifierError(Box<dyn std::error::Error + Sync + Send>)
+    GenericTokenVerifierError(Box<dyn std::error::Error + Sync + Send>),
+    // For token verifiers, the claims are not acceptable
+    TokenRejected(String),
+    // For token verifiers, no decision could be made
+    VerifierUnavailable(Box<dyn std::error::Error + Sync + Send>)
 }
 
 impl From<std::io::Error> for RaTlsError {
@@ -118,10 +171,43 @@ impl From<pkcs8::der::Error> for RaTlsError {
     }
 }
 
+impl From<webpki::Error> for RaTlsError {
+    fn from(value: webpki::Error) -> Self {
+        Self::WebpkiError(value)
+    }
+}
+
+impl From<rustls::server::VerifierBuilderError> for RaTlsError {
+    fn from(value: rustls::server::VerifierBuilderError) -> Self {
+        Self::VerifierBuilderError(value)
+    }
+}
+
+impl From<ciborium::de::Error<std::io::Error>> for RaTlsError {
+    fn from(value: ciborium::de::Error<std::io::Error>) -> Self {
+        Self::CborDecodeError(value)
+    }
+}
+
+impl From<ciborium::ser::Error<std::io::Error>> for RaTlsError {
+    fn from(value: ciborium::ser::Error<std::io::Error>) -> Self {
+        Self::CborEncodeError(value)
+    }
+}
+
+impl From<serde_json::Error> for RaTlsError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::JsonError(value)
+    }
+}
+
 impl Error for RaTlsError {}
 
 impl Display for RaTlsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::AttestationFailed(report) => write!(f, "Attestation failed, {}", report),
+            _ => write!(f, "{:?}", self)
+        }
     }
 }
@@ -0,0 +1,207 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Debug, sync::Arc};
+use log::{error, info};
+use rust_rsi::{print_token, verify_token, PlatformClaims, RealmClaims};
+use sha2::{Digest, Sha256};
+use crate::{attestation::PeerAttestation, cmw::{Evidence, CCA_MEDIA_TYPE}, error::RaTlsError};
+use crate::token_verifier::InternalTokenVerifier;
+
+// Claims of the verified evidence, CCA ones are kept typed
+#[derive(Debug)]
+pub enum EvidenceClaims {
+    Cca {
+        realm: Box<RealmClaims>,
+        platform: Box<PlatformClaims>
+    },
+    Generic(BTreeMap<String, Vec<u8>>)
+}
+
+// Format independent result of the evidence verification
+#[derive(Debug)]
+pub struct VerifiedEvidence {
+    // Value that has to be equal to Hash(nonce || Public Key)
+    pub challenge: Vec<u8>,
+    // Stable fingerprint of the attested workload
+    pub identity: Vec<u8>,
+    pub claims: EvidenceClaims
+}
+
+// Verifies the signature of one evidence format and extracts its claims.
+// The appraisal of the claims is left to the InternalTokenVerifier.
+pub trait EvidenceVerifier: Debug + Send + Sync {
+    fn media_type(&self) -> &str;
+    fn verify(&self, evidence: &[u8]) -> Result<VerifiedEvidence, RaTlsError>;
+}
+
+#[derive(Debug)]
+pub struct CcaEvidenceVerifier;
+
+impl EvidenceVerifier for CcaEvidenceVerifier {
+    fn media_type(&self) -> &str {
+        CCA_MEDIA_TYPE
+    }
+
+    fn verify(&self, evidence: &[u8]) -> Result<VerifiedEvidence, RaTlsError> {
+        let token = verify_token(evidence, None)?;
+        let realm = RealmClaims::from_raw_claims(&token.realm_claims.token_claims, &token.realm_claims.measurement_claims)?;
+        let platform = PlatformClaims::from_raw_claims(&token.platform_claims.token_claims, &token.platform_claims.sw_components)?;
+
+        info!("Received CCA token:");
+        print_token(&token);
+
+        Ok(VerifiedEvidence {
+            challenge: realm.challenge.clone(),
+            identity: realm_identity(&realm),
+            claims: EvidenceClaims::Cca { realm: Box::new(realm), platform: Box::new(platform) }
+        })
+    }
+}
+
+// Fingerprint of the realm measured at creation time (RIM, RPV and the
+// hash algorithm). REMs are left out as they are extended at runtime.
+fn realm_identity(claims: &RealmClaims) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+
+    for field in [claims.hash_algo.as_bytes(), &claims.rim, &claims.personalization_value] {
+        hasher.update((field.len() as u32).to_be_bytes());
+        hasher.update(field);
+    }
+
+    hasher.finalize().to_vec()
+}
+
+// Evidence verifiers keyed by the media type, CCA is always registered.
+#[derive(Debug, Clone)]
+pub struct EvidenceVerifiers {
+    verifiers: HashMap<String, Arc<dyn EvidenceVerifier>>
+}
+
+impl Default for EvidenceVerifiers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EvidenceVerifiers {
+    pub fn new() -> Self {
+        Self { verifiers: HashMap::new() }.with_verifier(Arc::new(CcaEvidenceVerifier))
+    }
+
+    // Replaces the verifier previously registered for the same media type.
+    pub fn with_verifier(mut self, verifier: Arc<dyn EvidenceVerifier>) -> Self {
+        self.verifiers.insert(verifier.media_type().to_owned(), verifier);
+        self
+    }
+
+    pub(crate) fn get(&self, media_type: &str) -> Option<&Arc<dyn EvidenceVerifier>> {
+        self.verifiers.get(media_type)
+    }
+
+    // Registered media types, CCA first
+    pub(crate) fn media_types(&self) -> Vec<&str> {
+        let mut media_types = self.verifiers.keys().map(String::as_str).collect::<Vec<_>>();
+        media_types.sort_by_key(|media_type| (*media_type != CCA_MEDIA_TYPE, *media_type));
+        media_types
+    }
+
+    // Bare evidence carries no type. Older peers send bare CCA tokens, so
+    // CCA is tried first and then the other formats by their media types.
+    fn verify_bare(&self, evidence: &[u8]) -> (String, Result<VerifiedEvidence, RaTlsError>) {
+        let mut verifiers = self.verifiers.iter().collect::<Vec<_>>();
+        verifiers.sort_by_key(|(media_type, _)| (media_type.as_str() != CCA_MEDIA_TYPE, media_type.as_str()));
+
+        let mut first_error = None;
+        for (media_type, verifier) in verifiers {
+            match verifier.verify(evidence) {
+                Ok(verified) => return (media_type.clone(), Ok(verified)),
+                Err(err) => { first_error.get_or_insert(err); }
+            }
+        }
+
+        let err = first_error.unwrap_or_else(|| RaTlsError::UnsupportedEvidenceType(CCA_MEDIA_TYPE.to_owned()));
+        (CCA_MEDIA_TYPE.to_owned(), Err(err))
+    }
+
+    // Verifies the evidence, its binding to the expected challenge and then
+    // passes the token to the application specific verifier.
+    pub(crate) fn verify_evidence(
+        &self,
+        evidence: Evidence,
+        challenge: &[u8],
+        token_verifier: &dyn InternalTokenVerifier
+    ) -> Result<PeerAttestation, RaTlsError> {
+        let raw_token = evidence.value;
+        let (media_type, verified) = match evidence.media_type {
+            Some(media_type) => {
+                let evidence_verifier = self.get(&media_type).ok_or_else(|| {
+                    error!("Unsupported evidence type: {}", media_type);
+                    RaTlsError::UnsupportedEvidenceType(media_type.clone())
+                })?;
+                let verified = evidence_verifier.verify(&raw_token);
+                (media_type, verified)
+            },
+            None => self.verify_bare(&raw_token)
+        };
+        let verified = verified.inspect_err(|_| {error!("Token verification failed")})?;
+
+        if challenge != verified.challenge {
+            error!("Challenge mismatch, expected:\n{:02X?}\nand got:\n{:02X?}", challenge, verified.challenge);
+            #[cfg(not(feature = "disable-challenge"))]
+            return Err(RaTlsError::InvalidChallenge);
+        }
+
+        info!("Received {} evidence", media_type);
+
+        token_verifier.verify(&raw_token).inspect_err(|_| {error!("Token verification failed");})?;
+
+        Ok(PeerAttestation::new(media_type, raw_token, verified))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cmw::{self, EvidenceEncoding};
+    use crate::sample_evidence::{SampleEvidenceVerifier, SAMPLE_MEDIA_TYPE};
+    use crate::token_verifier::SkipVerification;
+    use crate::tools::read_file;
+    use super::*;
+
+    // sample-evidence.cbor is signed for an all zero challenge
+    const VECTOR_CHALLENGE: [u8; 64] = [0; 64];
+
+    fn vector(name: &str) -> Vec<u8> {
+        read_file(format!("{}/vectors/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    fn verifiers(trusted_keys: Vec<Vec<u8>>) -> EvidenceVerifiers {
+        EvidenceVerifiers::new().with_verifier(Arc::new(SampleEvidenceVerifier::new(trusted_keys)))
+    }
+
+    fn verify(verifiers: &EvidenceVerifiers, encoding: EvidenceEncoding, challenge: &[u8]) -> Result<PeerAttestation, RaTlsError> {
+        let evidence = cmw::wrap(SAMPLE_MEDIA_TYPE, vector("sample-evidence.cbor"), encoding)?;
+        verifiers.verify_evidence(cmw::unwrap(&evidence), challenge, &SkipVerification)
+    }
+
+    #[test]
+    fn sample_vector_verifies_in_every_encoding() {
+        let verifiers = verifiers(vec![vector("sample-key.pub")]);
+
+        for encoding in [EvidenceEncoding::Raw, EvidenceEncoding::CmwCbor, EvidenceEncoding::CmwJson] {
+            let attestation = verify(&verifiers, encoding, &VECTOR_CHALLENGE).unwrap();
+            assert_eq!(attestation.media_type(), SAMPLE_MEDIA_TYPE);
+            assert_eq!(attestation.challenge(), VECTOR_CHALLENGE);
+        }
+    }
+
+    #[test]
+    fn sample_vector_needs_trusted_key() {
+        let result = verify(&verifiers(Vec::new()), EvidenceEncoding::CmwCbor, &VECTOR_CHALLENGE);
+        assert!(matches!(result, Err(RaTlsError::UntrustedEvidenceKey)));
+    }
+
+    #[test]
+    #[cfg(not(feature = "disable-challenge"))]
+    fn sample_vector_is_bound_to_challenge() {
+        let result = verify(&verifiers(vec![vector("sample-key.pub")]), EvidenceEncoding::Raw, &[1; 64]);
+        assert!(matches!(result, Err(RaTlsError::InvalidChallenge)));
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt::Display;
+use rustls::AlertDescription;
+use crate::{cert_verifier::RaTlsCertVeryfier, error::RaTlsError};
+
+// Why the attestation of the peer was refused. The codes are stable and can
+// be relied on by logs, metrics and applications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AttestationFailure {
+    MissingEvidence,
+    MalformedEvidence,
+    UnsupportedEvidenceType,
+    InvalidEvidence,
+    UntrustedEvidenceKey,
+    ChallengeMismatch,
+    ServerNameMismatch,
+    InvalidCertificate,
+    // The token verifier refused the claims, e.g. an unknown measurement
+    PolicyRejected,
+    // The token verifier could not reach a decision, e.g. service unreachable
+    VerifierUnavailable,
+    // The token verifier failed without telling a verdict from a failure to
+    // reach one
+    VerifierError,
+    Internal
+}
+
+impl AttestationFailure {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MissingEvidence => "missing-evidence",
+            Self::MalformedEvidence => "malformed-evidence",
+            Self::UnsupportedEvidenceType => "unsupported-evidence-type",
+            Self::InvalidEvidence => "invalid-evidence",
+            Self::UntrustedEvidenceKey => "untrusted-evidence-key",
+            Self::ChallengeMismatch => "challenge-mismatch",
+            Self::ServerNameMismatch => "server-name-mismatch",
+            Self::InvalidCertificate => "invalid-certificate",
+            Self::PolicyRejected => "policy-rejected",
+            Self::VerifierUnavailable => "verifier-unavailable",
+            Self::VerifierError => "verifier-error",
+            Self::Internal => "internal-error"
+        }
+    }
+
+    // Alert sent to the peer. rustls has no way to send internal_error, so
+    // the failures that are not about the certificate get handshake_failure.
+    pub fn alert(&self) -> AlertDescription {
+        match self {
+            Self::MissingEvidence
+            | Self::MalformedEvidence => AlertDescription::BadCertificate,
+            Self::UnsupportedEvidenceType
+            | Self::InvalidEvidence
+            | Self::UntrustedEvidenceKey
+            | Self::ChallengeMismatch
+            | Self::ServerNameMismatch
+            | Self::InvalidCertificate
+            | Self::PolicyRejected
+            | Self::VerifierError => AlertDescription::CertificateUnknown,
+            Self::VerifierUnavailable
+            | Self::Internal => AlertDescription::HandshakeFailure
+        }
+    }
+}
+
+impl From<&RaTlsError> for AttestationFailure {
+    fn from(value: &RaTlsError) -> Self {
+        match value {
+            RaTlsError::MissingTokenInCertificate
+            | RaTlsError::MissingPeerEvidence => Self::MissingEvidence,
+            RaTlsError::CannotExtractTokenFromExtension
+            | RaTlsError::InvalidCmw
+            | RaTlsError::CborDecodeError(_)
+            | RaTlsError::JsonError(_)
+            | RaTlsError::Base64DecodeError(_)
+            | RaTlsError::Utf8DecodingError(_)
+            | RaTlsError::Asn1DecodeError(_)
+            | RaTlsError::InvalidSampleEvidence
+            | RaTlsError::InvalidKeyOrigin
+            | RaTlsError::InvalidAttestationFrame => Self::MalformedEvidence,
+            RaTlsError::UnsupportedEvidenceType(_) => Self::UnsupportedEvidenceType,
+            RaTlsError::RustRsiTokenError(_)
+            | RaTlsError::InvalidCCAToken
+            | RaTlsError::InvalidEvidenceSignature => Self::InvalidEvidence,
+            RaTlsError::UntrustedEvidenceKey => Self::UntrustedEvidenceKey,
+            RaTlsError::InvalidChallenge
+            | RaTlsError::InvalidInclusionProof => Self::ChallengeMismatch,
+            RaTlsError::InvalidServerName
+            | RaTlsError::WebpkiError(webpki::Error::CertNotValidForName(_)) => Self::ServerNameMismatch,
+            RaTlsError::CertParsingError(_)
+            | RaTlsError::WebpkiError(_) => Self::InvalidCertificate,
+            RaTlsError::TokenRejected(_) => Self::PolicyRejected,
+            RaTlsError::VerifierUnavailable(_) => Self::VerifierUnavailable,
+            // Verifiers that don't tell a verdict from a failure to reach one
+            RaTlsError::GenericTokenVerifierError(_) => Self::VerifierError,
+            RaTlsError::AttestationFailed(report) => report.failure(),
+            _ => Self::Internal
+        }
+    }
+}
+
+// Machine readable description of a failed attestation, returned by connect
+// and accept when the peer's evidence was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttestationReport {
+    failure: AttestationFailure,
+    detail: String
+}
+
+impl AttestationReport {
+    pub fn failure(&self) -> AttestationFailure {
+        self.failure
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.failure.code()
+    }
+
+    pub fn alert(&self) -> AlertDescription {
+        self.failure.alert()
+    }
+
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "code": self.code(),
+            "alert": format!("{:?}", self.alert()),
+            "detail": self.detail
+        }).to_string()
+    }
+}
+
+impl From<&RaTlsError> for AttestationReport {
+    fn from(value: &RaTlsError) -> Self {
+        match value {
+            RaTlsError::AttestationFailed(report) => report.clone(),
+            _ => Self { failure: AttestationFailure::from(value), detail: format!("{:?}", value) }
+        }
+    }
+}
+
+// Handshake errors caused by refusing the peer are replaced with the report.
+pub(crate) fn with_report(err: RaTlsError, verifier: Option<&RaTlsCertVeryfier>) -> RaTlsError {
+    match verifier.and_then(|verifier| verifier.failure()) {
+        Some(report) => RaTlsError::AttestationFailed(report),
+        None => err
+    }
+}
+
+impl Display for AttestationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code(), self.detail)
+    }
+}
@@ -0,0 +1,186 @@
+use std::{sync::{atomic::AtomicU64, Arc}, time::Instant};
+use ciborium::Value;
+use rcgen::{KeyPair, PublicKeyData, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, PKCS_ED25519, PKCS_RSA_SHA256};
+use rustls::{crypto::ring::sign::any_supported_type, pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer}, sign::SigningKey, SignatureScheme};
+use simple_asn1::ASN1Block;
+use crate::{error::RaTlsError, tools::read_file};
+use crate::tools::parse_private_key_from_pem;
+
+// Where the key of the RA-TLS certificate comes from. It is put into the
+// certificate, so verifiers can treat keys that survive restarts differently.
+// The extension is not covered by the evidence, it is only what the peer
+// claims about its own key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyOrigin {
+    // Generated by the process, lost when it exits
+    Ephemeral,
+    // Loaded from a file or from memory
+    Persistent,
+    // Held by the application, e.g. in an HSM
+    External
+}
+
+impl KeyOrigin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ephemeral => "ephemeral",
+            Self::Persistent => "persistent",
+            Self::External => "external"
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, RaTlsError> {
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&Value::Text(self.name().to_owned()), &mut encoded)?;
+        Ok(encoded)
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> Result<Self, RaTlsError> {
+        match ciborium::from_reader(data)? {
+            Value::Text(name) if name == "ephemeral" => Ok(Self::Ephemeral),
+            Value::Text(name) if name == "persistent" => Ok(Self::Persistent),
+            Value::Text(name) if name == "external" => Ok(Self::External),
+            _ => Err(RaTlsError::InvalidKeyOrigin)
+        }
+    }
+}
+
+// Signature schemes usable for the certificate, in the order of preference
+const CERT_SIGNATURE_SCHEMES: [(SignatureScheme, &SignatureAlgorithm); 4] = [
+    (SignatureScheme::ECDSA_NISTP256_SHA256, &PKCS_ECDSA_P256_SHA256),
+    (SignatureScheme::ECDSA_NISTP384_SHA384, &PKCS_ECDSA_P384_SHA384),
+    (SignatureScheme::ED25519, &PKCS_ED25519),
+    (SignatureScheme::RSA_PKCS1_SHA256, &PKCS_RSA_SHA256)
+];
+
+// Lets rcgen sign the certificate with any rustls signing key
+#[derive(Debug)]
+pub(crate) struct KeySigner {
+    signing_key: Arc<dyn SigningKey>,
+    scheme: SignatureScheme,
+    algorithm: &'static SignatureAlgorithm,
+    public_key: Vec<u8>
+}
+
+impl KeySigner {
+    pub(crate) fn new(signing_key: Arc<dyn SigningKey>) -> Result<Self, RaTlsError> {
+        let (scheme, algorithm) = CERT_SIGNATURE_SCHEMES
+            .iter()
+            .find(|(scheme, _)| signing_key.choose_scheme(&[*scheme]).is_some())
+            .copied()
+            .ok_or(RaTlsError::UnsupportedSigningKey)?;
+        let spki = signing_key.public_key().ok_or(RaTlsError::UnsupportedSigningKey)?;
+
+        // rcgen wants the subjectPublicKey bits only
+        let public_key = match simple_asn1::from_der(spki.as_ref())?.as_slice() {
+            [ASN1Block::Sequence(_, items)] => match items.as_slice() {
+                [ASN1Block::Sequence(..), ASN1Block::BitString(_, _, public_key)] => public_key.clone(),
+                _ => return Err(RaTlsError::UnsupportedSigningKey)
+            },
+            _ => return Err(RaTlsError::UnsupportedSigningKey)
+        };
+
+        Ok(Self { signing_key, scheme, algorithm, public_key })
+    }
+}
+
+impl PublicKeyData for KeySigner {
+    fn der_bytes(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        self.algorithm
+    }
+}
+
+impl rcgen::SigningKey for KeySigner {
+    fn sign(&self, msg: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+        self.signing_key
+            .choose_scheme(&[self.scheme])
+            .ok_or(rcgen::Error::RemoteKeyError)?
+            .sign(msg)
+            .map_err(|_| rcgen::Error::RemoteKeyError)
+    }
+}
+
+// Key of the RA-TLS certificates, the evidence is bound to its public key
+#[derive(Debug)]
+pub(crate) struct ResolverKey {
+    pub(crate) signer: KeySigner,
+    // This is exactly the SubjectPublicKeyInfo rcgen puts in the certificate
+    pub(crate) public_key_der: Vec<u8>,
+    pub(crate) origin: KeyOrigin,
+    pub(crate) created: Instant,
+    pub(crate) handshakes: AtomicU64
+}
+
+impl ResolverKey {
+    pub(crate) fn generated(key_pair: KeyPair) -> Result<Self, RaTlsError> {
+        let signing_key = any_supported_type(&PrivateKeyDer::Pkcs8(
+            PrivatePkcs8KeyDer::from(key_pair.serialize_der())
+        ))?;
+        Self::with_origin(signing_key, KeyOrigin::Ephemeral)
+    }
+
+    pub(crate) fn with_origin(signing_key: Arc<dyn SigningKey>, origin: KeyOrigin) -> Result<Self, RaTlsError> {
+        let signer = KeySigner::new(signing_key)?;
+        let public_key_der = signer.subject_public_key_info();
+
+        Ok(Self { signer, public_key_der, origin, created: Instant::now(), handshakes: AtomicU64::new(0) })
+    }
+
+    pub(crate) fn signing_key(&self) -> Arc<dyn SigningKey> {
+        self.signer.signing_key.clone()
+    }
+}
+
+// PKCS#8 in DER or any private key in PEM
+pub(crate) fn load_private_key(path: impl AsRef<str>) -> Result<PrivateKeyDer<'static>, RaTlsError> {
+    let data = read_file(path)?;
+
+    if data.starts_with(b"-----BEGIN") {
+        parse_private_key_from_pem(&String::from_utf8(data)?)
+    } else {
+        Ok(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    fn key_file(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("ratls-{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn load(name: &str, data: &[u8]) -> Result<PrivateKeyDer<'static>, RaTlsError> {
+        let path = key_file(name, data);
+        let key = load_private_key(path.to_str().unwrap());
+        std::fs::remove_file(path).unwrap();
+        key
+    }
+
+    #[test]
+    fn key_file_is_der_or_pem() {
+        let key_pair = KeyPair::generate().unwrap();
+
+        let key = load("key.der", &key_pair.serialize_der()).unwrap();
+        assert_eq!(key.secret_der(), key_pair.serialize_der());
+
+        let key = load("key.pem", key_pair.serialize_pem().as_bytes()).unwrap();
+        assert_eq!(key.secret_der(), key_pair.serialize_der());
+    }
+
+    #[test]
+    fn garbage_key_file_is_refused() {
+        assert!(load("garbage.pem", b"-----BEGIN GARBAGE-----\n").is_err());
+
+        // Anything else is taken for DER, it fails once used as a key
+        let key = load("garbage.der", b"garbage").unwrap();
+        assert!(any_supported_type(&key).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{collections::VecDeque, sync::{Arc, Condvar, Mutex}, thread};
+use log::{debug, error};
+use rcgen::KeyPair;
+use crate::{cert_resolver::KeyType, error::RaTlsError};
+
+#[derive(Debug, Default)]
+struct PoolState {
+    keys: VecDeque<KeyPair>,
+    stopped: bool,
+    // Set if the generator thread gave up
+    failed: bool
+}
+
+#[derive(Debug)]
+struct PoolShared {
+    key_type: KeyType,
+    size: usize,
+    state: Mutex<PoolState>,
+    changed: Condvar
+}
+
+// Keys generated ahead of time on a background thread, so connections that
+// need a fresh key don't wait for the key generation. The thread exits when
+// the pool is dropped.
+#[derive(Debug)]
+pub(crate) struct KeyPool {
+    shared: Arc<PoolShared>
+}
+
+impl KeyPool {
+    pub(crate) fn new(key_type: KeyType, size: usize) -> Result<Self, RaTlsError> {
+        let shared = Arc::new(PoolShared {
+            key_type,
+            size: size.max(1),
+            state: Mutex::new(PoolState::default()),
+            changed: Condvar::new()
+        });
+
+        let generator = shared.clone();
+        thread::Builder::new()
+            .name("ratls-key-pool".to_owned())
+            .spawn(move || generator.fill())?;
+
+        Ok(Self { shared })
+    }
+
+    // Waits for the generator if the pool is empty, it would take as long to
+    // generate the key here and both would compete for the CPU.
+    pub(crate) fn take(&self) -> Result<KeyPair, RaTlsError> {
+        let key_pair = self.shared.state.lock().ok().and_then(|mut state| {
+            while state.keys.is_empty() && !state.failed {
+                debug!("Key pool is empty, waiting for a {:?} key", self.shared.key_type);
+                state = self.shared.changed.wait(state).ok()?;
+            }
+            state.keys.pop_front()
+        });
+        self.shared.changed.notify_all();
+
+        match key_pair {
+            Some(key_pair) => Ok(key_pair),
+            None => self.shared.key_type.generate()
+        }
+    }
+}
+
+impl Drop for KeyPool {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.stopped = true;
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+impl PoolShared {
+    fn fill(&self) {
+        loop {
+            {
+                let Ok(mut state) = self.state.lock() else {
+                    return;
+                };
+                while !state.stopped && state.keys.len() >= self.size {
+                    state = match self.changed.wait(state) {
+                        Ok(state) => state,
+                        Err(_) => return
+                    };
+                }
+                if state.stopped {
+                    return;
+                }
+            }
+
+            // Generated without holding the lock, it may take seconds for RSA
+            let key_pair = match self.key_type.generate() {
+                Ok(key_pair) => key_pair,
+                Err(err) => {
+                    error!("Failed to generate {:?} key for the pool: {:?}", self.key_type, err);
+                    if let Ok(mut state) = self.state.lock() {
+                        state.failed = true;
+                    }
+                    self.changed.notify_all();
+                    return;
+                }
+            };
+
+            match self.state.lock() {
+                Ok(mut state) => state.keys.push_back(key_pair),
+                Err(_) => return
+            }
+            self.changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_pool_waits_for_the_generator() {
+        let pool = KeyPool::new(KeyType::EcdsaP256, 1).unwrap();
+        let keys: Vec<_> = (0..3).map(|_| pool.take().unwrap().serialize_der()).collect();
+
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[1], keys[2]);
+        assert_ne!(keys[0], keys[2]);
+    }
+
+    #[test]
+    fn empty_pool_generates_once_the_generator_gave_up() {
+        let pool = KeyPool::new(KeyType::EcdsaP256, 1).unwrap();
+        {
+            let mut state = pool.shared.state.lock().unwrap();
+            state.stopped = true;
+            state.failed = true;
+            state.keys.clear();
+        }
+
+        assert!(pool.take().is_ok());
+    }
+}
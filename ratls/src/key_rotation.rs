@@ -0,0 +1,258 @@
+use std::{fmt::Debug, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, RwLock}, thread, time::Duration};
+use log::{error, info};
+use crate::{cert_resolver::KeyType, error::RaTlsError, identity::{KeyOrigin, ResolverKey}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationReason {
+    Age,
+    Handshakes
+}
+
+// Emitted once the new key is in use
+#[derive(Debug, Clone)]
+pub struct KeyRotation {
+    pub reason: RotationReason,
+    // SubjectPublicKeyInfo of the replaced and of the new key
+    pub previous_public_key: Vec<u8>,
+    pub public_key: Vec<u8>,
+    // Lifetime of the replaced key
+    pub age: Duration,
+    pub handshakes: u64
+}
+
+pub trait KeyRotationListener: Debug + Send + Sync {
+    fn key_rotated(&self, rotation: &KeyRotation);
+}
+
+// The key is replaced once it is older than max_age or has been used for
+// max_handshakes certificates, whichever comes first. The new key is
+// generated in the background and the old one is used until it is ready,
+// unless it is past max_age. Such a key is never used, the handshake waits
+// for the new one instead.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRotationPolicy {
+    max_age: Option<Duration>,
+    max_handshakes: Option<u64>,
+    listener: Option<Arc<dyn KeyRotationListener>>
+}
+
+impl KeyRotationPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn with_max_handshakes(mut self, max_handshakes: u64) -> Self {
+        self.max_handshakes = Some(max_handshakes.max(1));
+        self
+    }
+
+    pub fn with_listener(mut self, listener: Arc<dyn KeyRotationListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    fn due(&self, key: &ResolverKey, handshakes: u64) -> Option<RotationReason> {
+        if self.max_handshakes.is_some_and(|max_handshakes| handshakes >= max_handshakes) {
+            Some(RotationReason::Handshakes)
+        } else if self.expired(key) {
+            Some(RotationReason::Age)
+        } else {
+            None
+        }
+    }
+
+    fn expired(&self, key: &ResolverKey) -> bool {
+        self.max_age.is_some_and(|max_age| key.created.elapsed() >= max_age)
+    }
+}
+
+// Handshakes take the current key and keep it until their certificate is
+// done, so swapping the key does not disturb them.
+#[derive(Debug)]
+pub(crate) struct RotatingKey {
+    key_type: KeyType,
+    current: RwLock<Arc<ResolverKey>>,
+    policy: Option<KeyRotationPolicy>,
+    rotating: AtomicBool,
+    // Held while the new key is generated, so it is made only once
+    generating: Mutex<()>
+}
+
+impl RotatingKey {
+    pub(crate) fn new(key_type: KeyType, key: ResolverKey) -> Self {
+        Self {
+            key_type,
+            current: RwLock::new(Arc::new(key)),
+            policy: None,
+            rotating: AtomicBool::new(false),
+            generating: Mutex::new(())
+        }
+    }
+
+    // Only generated keys can be replaced, loaded and external ones are kept
+    pub(crate) fn set_policy(&mut self, policy: KeyRotationPolicy) -> Result<(), RaTlsError> {
+        let origin = self.installed().origin;
+        if origin != KeyOrigin::Ephemeral {
+            error!("The {} key can't be rotated", origin.name());
+            return Err(RaTlsError::KeyRotationUnsupported);
+        }
+        self.policy = Some(policy);
+        Ok(())
+    }
+
+    // The key for a new certificate, replaced right away if past max_age
+    pub(crate) fn current(&self) -> Result<Arc<ResolverKey>, RaTlsError> {
+        let key = self.installed();
+        if !self.policy.as_ref().is_some_and(|policy| policy.expired(&key)) {
+            return Ok(key);
+        }
+        self.rotate(RotationReason::Age, &key)
+    }
+
+    fn installed(&self) -> Arc<ResolverKey> {
+        match self.current.read() {
+            Ok(current) => current.clone(),
+            Err(poisoned) => poisoned.into_inner().clone()
+        }
+    }
+
+    // Counts a certificate made with the key and starts the rotation if due.
+    pub(crate) fn record_use(self: &Arc<Self>, key: &Arc<ResolverKey>) {
+        let handshakes = key.handshakes.fetch_add(1, Ordering::AcqRel) + 1;
+        let Some(reason) = self.policy.as_ref().and_then(|policy| policy.due(key, handshakes)) else {
+            return;
+        };
+        // Handshakes still finishing with a replaced key don't count
+        if !Arc::ptr_eq(&self.installed(), key) || self.rotating.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let (rotating_key, key) = (self.clone(), key.clone());
+        let spawned = thread::Builder::new()
+            .name("ratls-key-rotation".to_owned())
+            .spawn(move || {
+                if let Err(err) = rotating_key.rotate(reason, &key) {
+                    error!("Key rotation failed: {:?}", err);
+                }
+                rotating_key.rotating.store(false, Ordering::Release);
+            });
+        if let Err(err) = spawned {
+            error!("Failed to start the key rotation: {:?}", err);
+            self.rotating.store(false, Ordering::Release);
+        }
+    }
+
+    // Replaces the key unless that was done meanwhile, returns the new key.
+    fn rotate(&self, reason: RotationReason, replaced: &Arc<ResolverKey>) -> Result<Arc<ResolverKey>, RaTlsError> {
+        let _generating = self.generating.lock().map_err(|_| RaTlsError::KeyRotationFailed)?;
+        let installed = self.installed();
+        if !Arc::ptr_eq(&installed, replaced) {
+            return Ok(installed);
+        }
+
+        info!("Rotating {:?} key, reason: {:?}", self.key_type, reason);
+        let key = Arc::new(ResolverKey::generated(self.key_type.generate()?)?);
+        let public_key = key.public_key_der.clone();
+
+        let previous = {
+            let mut current = self.current.write().map_err(|_| RaTlsError::KeyRotationFailed)?;
+            std::mem::replace(&mut *current, key.clone())
+        };
+
+        let rotation = KeyRotation {
+            reason,
+            previous_public_key: previous.public_key_der.clone(),
+            public_key,
+            age: previous.created.elapsed(),
+            handshakes: previous.handshakes.load(Ordering::Acquire)
+        };
+        if let Some(listener) = self.policy.as_ref().and_then(|policy| policy.listener.as_ref()) {
+            listener.key_rotated(&rotation);
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use crate::{builder::RaTlsServerBuilder, config::AttestationTransport, sample_evidence::SampleEvidenceResolver};
+    use super::*;
+
+    #[derive(Debug)]
+    struct Rotations(mpsc::Sender<KeyRotation>);
+
+    impl KeyRotationListener for Rotations {
+        fn key_rotated(&self, rotation: &KeyRotation) {
+            let _ = self.0.send(rotation.clone());
+        }
+    }
+
+    #[test]
+    fn key_is_rotated_after_max_handshakes() {
+        let (sender, rotations) = mpsc::channel();
+        let policy = KeyRotationPolicy::new().with_max_handshakes(2).with_listener(Arc::new(Rotations(sender)));
+        let mut key = RotatingKey::new(KeyType::EcdsaP256, ResolverKey::generated(KeyType::EcdsaP256.generate().unwrap()).unwrap());
+        key.set_policy(policy).unwrap();
+        let key = Arc::new(key);
+
+        let first = key.current().unwrap();
+        key.record_use(&first);
+        key.record_use(&first);
+
+        let rotation = rotations.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(rotation.reason, RotationReason::Handshakes);
+        assert_eq!(rotation.handshakes, 2);
+        assert_eq!(rotation.previous_public_key, first.public_key_der);
+        assert_eq!(rotation.public_key, key.current().unwrap().public_key_der);
+        assert_ne!(rotation.public_key, rotation.previous_public_key);
+    }
+
+    #[test]
+    fn key_past_max_age_is_never_handed_out() {
+        let (sender, rotations) = mpsc::channel();
+        let policy = KeyRotationPolicy::new().with_max_age(Duration::ZERO).with_listener(Arc::new(Rotations(sender)));
+        let mut key = RotatingKey::new(KeyType::EcdsaP256, ResolverKey::generated(KeyType::EcdsaP256.generate().unwrap()).unwrap());
+        key.set_policy(policy).unwrap();
+
+        // Never used, so only the age makes it due
+        let expired = key.installed();
+        let current = key.current().unwrap();
+        assert!(!Arc::ptr_eq(&expired, &current));
+
+        let rotation = rotations.try_recv().unwrap();
+        assert_eq!(rotation.reason, RotationReason::Age);
+        assert_eq!(rotation.handshakes, 0);
+        assert_eq!(rotation.previous_public_key, expired.public_key_der);
+        assert_eq!(rotation.public_key, current.public_key_der);
+    }
+
+    #[test]
+    fn builder_refuses_rotation_it_can_not_apply() {
+        let token_resolver = Arc::new(SampleEvidenceResolver::generate(vec![0; 32]).unwrap());
+        let key_pair = KeyType::EcdsaP256.generate().unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+        let policy = KeyRotationPolicy::new().with_max_handshakes(1);
+
+        let supplied_key = RaTlsServerBuilder::new()
+            .with_attestation(token_resolver.clone())
+            .with_attestation_key(key)
+            .unwrap()
+            .with_key_rotation(policy.clone())
+            .build();
+        assert!(matches!(supplied_key, Err(RaTlsError::KeyRotationUnsupported)));
+
+        let post_handshake = RaTlsServerBuilder::new()
+            .with_attestation(token_resolver)
+            .with_attestation_transport(AttestationTransport::PostHandshake)
+            .with_key_rotation(policy)
+            .build();
+        assert!(matches!(post_handshake, Err(RaTlsError::KeyRotationUnsupported)));
+    }
+}
@@ -8,23 +8,90 @@ mod server;
 mod connection;
 mod tools;
 mod config;
+mod attestation;
+mod builder;
+mod cmw;
+mod evidence;
+mod sample_evidence;
+mod sni;
+mod post_handshake;
+mod channel_binding;
+mod failure;
+mod runner;
+mod rate_limit;
+mod batch;
+mod key_pool;
+mod key_rotation;
+mod identity;
+mod cert_profile;
+mod cert_issuer;
+mod policy;
+#[cfg(feature = "tokio")]
+mod async_connection;
+#[cfg(feature = "tokio")]
+mod async_server;
+#[cfg(feature = "tokio")]
+mod async_client;
 
 pub use error::RaTlsError;
+pub use failure::AttestationFailure;
+pub use failure::AttestationReport;
 
 pub use client::RaTlsClient;
 pub use client::ClientMode;
+pub use client::ClientConfigFactory;
 pub use server::RaTlsServer;
 pub use server::ServerMode;
+pub use server::ServerConfigFactory;
+pub use runner::ServeOptions;
+pub use runner::ServerHandle;
+pub use runner::ShutdownSignal;
 pub use connection::RaTlsConnection;
+pub use builder::RaTlsServerBuilder;
+pub use builder::RaTlsClientBuilder;
+pub use attestation::PeerAttestation;
+pub use channel_binding::ChannelBinding;
+
+#[cfg(feature = "tokio")]
+pub use async_connection::RaTlsStream;
+#[cfg(feature = "tokio")]
+pub use async_server::RaTlsAcceptor;
+#[cfg(feature = "tokio")]
+pub use async_client::RaTlsConnector;
 
 pub use token_resolver::InternalTokenResolver;
 pub use token_resolver::TokenFromFile;
 pub use token_verifier::InternalTokenVerifier;
 pub use token_verifier::SkipVerification;
 pub use token_verifier::ChainVerifier;
+pub use policy::PolicyVerifier;
 
 pub use cert_resolver::RaTlsCertResolver;
+pub use cert_resolver::KeyType;
+pub use identity::KeyOrigin;
+pub use cert_profile::CertificateProfile;
+pub use cert_issuer::CertificateIssuer;
+pub use cert_profile::SubjectAttribute;
+pub use cert_profile::KeyUsage;
+pub use cert_profile::ExtendedKeyUsage;
+pub use rate_limit::TokenRateLimit;
+pub use batch::TokenBatching;
+pub use key_rotation::KeyRotationPolicy;
+pub use key_rotation::KeyRotation;
+pub use key_rotation::KeyRotationListener;
+pub use key_rotation::RotationReason;
+pub use config::EvidenceExtension;
+pub use config::AttestationTransport;
+pub use cmw::EvidenceEncoding;
 pub use cert_verifier::RaTlsCertVeryfier;
+pub use evidence::EvidenceVerifier;
+pub use evidence::EvidenceVerifiers;
+pub use evidence::EvidenceClaims;
+pub use evidence::VerifiedEvidence;
+pub use evidence::CcaEvidenceVerifier;
+pub use sample_evidence::SampleEvidenceResolver;
+pub use sample_evidence::SampleEvidenceVerifier;
+pub use sample_evidence::SAMPLE_MEDIA_TYPE;
 
 pub use tools::init_logger;
 pub use tools::load_root_cert_store;
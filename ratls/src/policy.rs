@@ -0,0 +1,437 @@
+use std::{cmp::Ordering, collections::BTreeMap};
+use data_encoding::HEXLOWER_PERMISSIVE;
+use log::{error, info};
+use rust_rsi::{verify_token, PlatformClaims, RealmClaims};
+use serde_json::{Map, Value};
+use crate::{error::RaTlsError, token_verifier::InternalTokenVerifier, tools::read_file};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ClaimValue {
+    Bytes(Vec<u8>),
+    Text(String),
+    Number(u64)
+}
+
+impl ClaimValue {
+    fn equals(&self, expected: &Value) -> bool {
+        match (self, expected) {
+            (Self::Bytes(bytes), Value::String(hex)) => HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).is_ok_and(|expected| &expected == bytes),
+            (Self::Text(text), Value::String(expected)) => text == expected,
+            (Self::Number(number), Value::Number(expected)) => expected.as_u64() == Some(*number),
+            _ => false
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Bytes(bytes) => bytes.is_empty(),
+            Self::Text(text) => text.is_empty(),
+            Self::Number(_) => false
+        }
+    }
+
+    fn number(&self) -> Option<u64> {
+        match self {
+            Self::Number(number) => Some(*number),
+            _ => None
+        }
+    }
+}
+
+// The CCA claims flattened to the paths used by the policy, byte strings are
+// compared as hex. Software components are listed by their type, there may
+// be more than one of a type.
+#[derive(Debug, Default)]
+struct PolicyClaims {
+    values: BTreeMap<String, Vec<ClaimValue>>
+}
+
+impl PolicyClaims {
+    fn new(realm: &RealmClaims, platform: &PlatformClaims) -> Self {
+        let mut claims = Self::default();
+
+        claims.add("realm.rim", ClaimValue::Bytes(realm.rim.clone()));
+        for (index, rem) in realm.rems.iter().enumerate() {
+            claims.add(&format!("realm.rem{}", index), ClaimValue::Bytes(rem.clone()));
+        }
+        claims.add("realm.rpv", ClaimValue::Bytes(realm.personalization_value.clone()));
+        claims.add("realm.hash_algo", ClaimValue::Text(realm.hash_algo.clone()));
+        claims.add("realm.pub_key", ClaimValue::Bytes(realm.pub_key.clone()));
+        claims.add("realm.pub_key_hash_algo", ClaimValue::Text(realm.pub_key_hash_algo.clone()));
+
+        claims.add("platform.profile", ClaimValue::Text(platform.profile.clone()));
+        claims.add("platform.implementation_id", ClaimValue::Bytes(platform.implementation_id.clone()));
+        claims.add("platform.instance_id", ClaimValue::Bytes(platform.instance_id.clone()));
+        claims.add("platform.lifecycle", ClaimValue::Number(platform.lifecycle.into()));
+        claims.add("platform.config", ClaimValue::Bytes(platform.configuration.clone()));
+        claims.add("platform.hash_algo", ClaimValue::Text(platform.hash_algo.clone()));
+        claims.add("platform.verification_service", ClaimValue::Text(platform.verification_service.clone()));
+
+        for component in &platform.sw_components {
+            let prefix = format!("platform.sw_components.{}", component.ty);
+            claims.add(&format!("{}.measurement", prefix), ClaimValue::Bytes(component.value.clone()));
+            claims.add(&format!("{}.version", prefix), ClaimValue::Text(component.version.clone()));
+            claims.add(&format!("{}.signer_id", prefix), ClaimValue::Bytes(component.signer_id.clone()));
+            claims.add(&format!("{}.hash_algo", prefix), ClaimValue::Text(component.hash_algo.clone()));
+        }
+
+        claims
+    }
+
+    // Empty claims were not in the token, so present does not hold for them
+    fn add(&mut self, path: &str, value: ClaimValue) {
+        if value.is_empty() {
+            return;
+        }
+        self.values.entry(path.to_owned()).or_default().push(value);
+    }
+}
+
+// Dotted versions are compared numerically, missing parts count as zero
+fn parse_version(version: &str) -> Option<Vec<u64>> {
+    version.trim_start_matches('v').split('.').map(|part| part.parse().ok()).collect()
+}
+
+fn compare_versions(left: &[u64], right: &[u64]) -> Ordering {
+    (0..left.len().max(right.len()))
+        .map(|index| left.get(index).unwrap_or(&0).cmp(right.get(index).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+#[derive(Debug, Clone)]
+enum Predicate {
+    Present,
+    Equals(Value),
+    In(Vec<Value>),
+    Min(u64),
+    Max(u64),
+    MinVersion(Vec<u64>)
+}
+
+impl Predicate {
+    fn holds(&self, value: &ClaimValue) -> bool {
+        match self {
+            Self::Present => true,
+            Self::Equals(expected) => value.equals(expected),
+            Self::In(allowed) => allowed.iter().any(|expected| value.equals(expected)),
+            Self::Min(min) => value.number().is_some_and(|number| number >= *min),
+            Self::Max(max) => value.number().is_some_and(|number| number <= *max),
+            Self::MinVersion(min) => match value {
+                ClaimValue::Text(version) => parse_version(version)
+                    .is_some_and(|version| compare_versions(&version, min).is_ge()),
+                _ => false
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Present => "is present".to_owned(),
+            Self::Equals(expected) => format!("equals {}", expected),
+            Self::In(allowed) => format!("is one of {} allowed values", allowed.len()),
+            Self::Min(min) => format!(">= {}", min),
+            Self::Max(max) => format!("<= {}", max),
+            Self::MinVersion(min) => format!(
+                "has version >= {}",
+                min.iter().map(u64::to_string).collect::<Vec<_>>().join(".")
+            )
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum RuleKind {
+    All(Vec<Rule>),
+    Any(Vec<Rule>),
+    Not(Box<Rule>),
+    // Every value of the claim has to satisfy the predicate, there has to
+    // be at least one
+    Claim(String, Predicate)
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: Option<String>,
+    kind: RuleKind
+}
+
+fn invalid(message: impl Into<String>) -> RaTlsError {
+    RaTlsError::InvalidPolicy(message.into())
+}
+
+impl Rule {
+    fn parse(value: &Value) -> Result<Self, RaTlsError> {
+        let Value::Object(object) = value else {
+            return Err(invalid(format!("rule must be an object: {}", value)));
+        };
+        let name = match object.get("name") {
+            None => None,
+            Some(Value::String(name)) => Some(name.clone()),
+            Some(_) => return Err(invalid("rule name must be a string"))
+        };
+        let keys = object.keys().filter(|key| *key != "name").map(String::as_str).collect::<Vec<_>>();
+
+        let kind = match keys.as_slice() {
+            ["all"] | ["rules"] => RuleKind::All(Self::parse_list(object.get(keys[0]))?),
+            ["any"] => RuleKind::Any(Self::parse_list(object.get("any"))?),
+            ["not"] => RuleKind::Not(Box::new(Self::parse(&object["not"])?)),
+            _ if object.contains_key("claim") => Self::parse_claim(object)?,
+            _ => return Err(invalid(format!("unknown rule: {}", value)))
+        };
+
+        Ok(Self { name, kind })
+    }
+
+    fn parse_list(value: Option<&Value>) -> Result<Vec<Self>, RaTlsError> {
+        match value {
+            Some(Value::Array(rules)) => rules.iter().map(Self::parse).collect(),
+            _ => Err(invalid("all, any and rules take a list of rules"))
+        }
+    }
+
+    fn parse_claim(object: &Map<String, Value>) -> Result<RuleKind, RaTlsError> {
+        let Some(Value::String(claim)) = object.get("claim") else {
+            return Err(invalid("claim must be a string"));
+        };
+        let predicates = object
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+            .filter(|(key, _)| !matches!(*key, "name" | "claim"))
+            .collect::<Vec<_>>();
+
+        let predicate = match predicates.as_slice() {
+            [("present", Value::Bool(true))] => Predicate::Present,
+            [("equals", expected)] => Predicate::Equals((*expected).clone()),
+            [("in", Value::Array(allowed))] => Predicate::In(allowed.clone()),
+            [("min", Value::Number(min))] => Predicate::Min(min.as_u64().ok_or_else(|| invalid("min must be an unsigned integer"))?),
+            [("max", Value::Number(max))] => Predicate::Max(max.as_u64().ok_or_else(|| invalid("max must be an unsigned integer"))?),
+            [("min_version", Value::String(min))] => {
+                Predicate::MinVersion(parse_version(min).ok_or_else(|| invalid(format!("invalid version: {}", min)))?)
+            },
+            _ => return Err(invalid(format!("claim {} needs exactly one of present, equals, in, min, max, min_version", claim)))
+        };
+
+        Ok(RuleKind::Claim(claim.clone(), predicate))
+    }
+
+    fn label(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+        match &self.kind {
+            RuleKind::All(rules) => format!("all of {} rules", rules.len()),
+            RuleKind::Any(rules) => format!("any of {} rules", rules.len()),
+            RuleKind::Not(rule) => format!("not ({})", rule.label()),
+            RuleKind::Claim(claim, predicate) => format!("{} {}", claim, predicate.describe())
+        }
+    }
+
+    // On failure returns the description of the rule that failed
+    fn evaluate(&self, claims: &PolicyClaims) -> Result<(), String> {
+        match &self.kind {
+            RuleKind::All(rules) => rules.iter().try_for_each(|rule| rule.evaluate(claims)),
+            RuleKind::Any(rules) => {
+                let mut failures = Vec::new();
+                for rule in rules {
+                    match rule.evaluate(claims) {
+                        Ok(()) => return Ok(()),
+                        Err(failed) => failures.push(failed)
+                    }
+                }
+                Err(format!("{}: [{}]", self.label(), failures.join("; ")))
+            },
+            RuleKind::Not(rule) => match rule.evaluate(claims) {
+                Ok(()) => Err(self.label()),
+                Err(_) => Ok(())
+            },
+            RuleKind::Claim(claim, predicate) => match claims.values.get(claim) {
+                Some(values) if values.iter().all(|value| predicate.holds(value)) => Ok(()),
+                Some(_) => Err(self.label()),
+                None => Err(format!("{} (claim {} is missing)", self.label(), claim))
+            }
+        }
+    }
+}
+
+// Appraises CCA tokens against a declarative policy. The policy is a rule,
+// or an object with a list of rules that all have to pass:
+//
+//   {"rules": [
+//     {"name": "trusted-realm", "claim": "realm.rim", "in": ["<hex>", "<hex>"]},
+//     {"claim": "platform.lifecycle", "min": 12288},
+//     {"claim": "platform.lifecycle", "max": 12543},
+//     {"claim": "platform.sw_components.BL2.version", "min_version": "1.9"},
+//     {"any": [{"claim": "realm.rem0", "equals": "<hex>"}, {"not": {"claim": "realm.rpv", "present": true}}]}
+//   ]}
+//
+// A rejected token is reported as TokenRejected naming the failed rule.
+#[derive(Debug, Clone)]
+pub struct PolicyVerifier {
+    policy: Rule
+}
+
+impl PolicyVerifier {
+    pub fn from_json(policy: &str) -> Result<Self, RaTlsError> {
+        Self::from_value(&serde_json::from_str(policy)?)
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_toml(policy: &str) -> Result<Self, RaTlsError> {
+        let policy: Value = toml::from_str(policy).map_err(|err| invalid(err.to_string()))?;
+        Self::from_value(&policy)
+    }
+
+    // TOML for files ending with .toml, JSON otherwise
+    pub fn from_file(path: impl AsRef<str>) -> Result<Self, RaTlsError> {
+        let policy = String::from_utf8(read_file(path.as_ref())?)?;
+
+        if path.as_ref().ends_with(".toml") {
+            #[cfg(feature = "toml")]
+            return Self::from_toml(&policy);
+            #[cfg(not(feature = "toml"))]
+            return Err(invalid("TOML policies need the toml feature"));
+        }
+        Self::from_json(&policy)
+    }
+
+    fn from_value(policy: &Value) -> Result<Self, RaTlsError> {
+        Ok(Self { policy: Rule::parse(policy)? })
+    }
+}
+
+impl InternalTokenVerifier for PolicyVerifier {
+    fn verify(&self, token: &[u8]) -> Result<(), RaTlsError> {
+        let token = verify_token(token, None)?;
+        let realm = RealmClaims::from_raw_claims(&token.realm_claims.token_claims, &token.realm_claims.measurement_claims)?;
+        let platform = PlatformClaims::from_raw_claims(&token.platform_claims.token_claims, &token.platform_claims.sw_components)?;
+
+        match self.policy.evaluate(&PolicyClaims::new(&realm, &platform)) {
+            Ok(()) => {
+                info!("Token satisfies the policy");
+                Ok(())
+            },
+            Err(failed) => {
+                error!("Policy rule failed: {}", failed);
+                Err(RaTlsError::TokenRejected(format!("policy rule failed: {}", failed)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use super::*;
+
+    fn parse(rule: Value) -> Result<Rule, RaTlsError> {
+        Rule::parse(&rule)
+    }
+
+    fn claims() -> PolicyClaims {
+        let mut claims = PolicyClaims::default();
+        claims.add("realm.rim", ClaimValue::Bytes(vec![0xab, 0xcd]));
+        claims.add("realm.rpv", ClaimValue::Bytes(Vec::new()));
+        claims.add("platform.lifecycle", ClaimValue::Number(12288));
+        claims.add("platform.sw_components.BL2.version", ClaimValue::Text("1.10.2".to_owned()));
+        claims.add("platform.sw_components.BL2.version", ClaimValue::Text("2.0".to_owned()));
+        claims
+    }
+
+    fn holds(rule: Value) -> bool {
+        parse(rule).unwrap().evaluate(&claims()).is_ok()
+    }
+
+    #[test]
+    fn invalid_rules_are_refused() {
+        for rule in [
+            json!("realm.rim"),
+            json!({"name": 1, "claim": "realm.rim", "present": true}),
+            json!({"unknown": []}),
+            json!({"all": {"claim": "realm.rim", "present": true}}),
+            json!({"claim": 1, "present": true}),
+            json!({"claim": "platform.lifecycle", "min": -1}),
+            json!({"claim": "platform.sw_components.BL2.version", "min_version": "1.x"}),
+            json!({"claim": "realm.rim", "present": false})
+        ] {
+            assert!(matches!(parse(rule), Err(RaTlsError::InvalidPolicy(_))));
+        }
+    }
+
+    #[test]
+    fn claim_rules_need_exactly_one_predicate() {
+        for rule in [
+            json!({"claim": "platform.lifecycle"}),
+            json!({"claim": "platform.lifecycle", "min": 1, "max": 2})
+        ] {
+            let Err(RaTlsError::InvalidPolicy(message)) = parse(rule) else {
+                panic!("rule was accepted");
+            };
+            assert!(message.contains("exactly one of"));
+        }
+    }
+
+    #[test]
+    fn empty_claims_are_missing() {
+        assert!(holds(json!({"claim": "realm.rim", "present": true})));
+        assert!(!holds(json!({"claim": "realm.rpv", "present": true})));
+        assert!(holds(json!({"not": {"claim": "realm.rpv", "present": true}})));
+
+        let failed = parse(json!({"claim": "realm.rpv", "equals": ""})).unwrap().evaluate(&claims()).unwrap_err();
+        assert!(failed.contains("is missing"));
+    }
+
+    #[test]
+    fn values_are_compared() {
+        assert!(holds(json!({"claim": "realm.rim", "equals": "ABCD"})));
+        assert!(!holds(json!({"claim": "realm.rim", "equals": "abce"})));
+        assert!(holds(json!({"claim": "realm.rim", "in": ["00", "abcd"]})));
+        assert!(!holds(json!({"claim": "realm.rim", "in": []})));
+
+        assert!(holds(json!({"claim": "platform.lifecycle", "min": 12288})));
+        assert!(holds(json!({"claim": "platform.lifecycle", "max": 12543})));
+        assert!(!holds(json!({"claim": "platform.lifecycle", "min": 12289})));
+        assert!(!holds(json!({"claim": "platform.lifecycle", "max": 12287})));
+        assert!(!holds(json!({"claim": "realm.rim", "min": 0})));
+    }
+
+    #[test]
+    fn versions_are_compared_numerically() {
+        // Every value of the claim has to pass
+        assert!(holds(json!({"claim": "platform.sw_components.BL2.version", "min_version": "1.9"})));
+        assert!(holds(json!({"claim": "platform.sw_components.BL2.version", "min_version": "v1.10.2.0"})));
+        assert!(!holds(json!({"claim": "platform.sw_components.BL2.version", "min_version": "1.11"})));
+    }
+
+    #[test]
+    fn rules_are_combined() {
+        let pass = json!({"claim": "platform.lifecycle", "min": 1});
+        let fail = json!({"claim": "platform.lifecycle", "max": 1});
+
+        assert!(holds(json!({"rules": [pass, pass]})));
+        assert!(!holds(json!({"all": [pass, fail]})));
+        assert!(holds(json!({"any": [fail, pass]})));
+        assert!(!holds(json!({"any": [fail, fail]})));
+        assert!(!holds(json!({"any": []})));
+        assert!(holds(json!({"not": fail})));
+        assert!(!holds(json!({"not": pass})));
+
+        let failed = parse(json!({"all": [pass, {"name": "bounded-lifecycle", "claim": "platform.lifecycle", "max": 1}]}))
+            .unwrap()
+            .evaluate(&claims())
+            .unwrap_err();
+        assert_eq!(failed, "bounded-lifecycle");
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_policies_are_parsed() {
+        let verifier = PolicyVerifier::from_toml(r#"
+            [[rules]]
+            name = "lifecycle"
+            claim = "platform.lifecycle"
+            min = 12288
+        "#).unwrap();
+        assert!(verifier.policy.evaluate(&claims()).is_ok());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{io::{Read, Write}, net::IpAddr, ops::{Deref, DerefMut}, sync::Arc, time::Instant};
+use log::error;
+use rustls::{ConnectionCommon, ProtocolVersion, SideData};
+use crate::{attestation::PeerAttestation, cmw::{self, EvidenceEncoding}, connection::RaTlsConnection, error::RaTlsError};
+use crate::channel_binding::ExchangedTokens;
+use crate::evidence::EvidenceVerifiers;
+use crate::failure::AttestationReport;
+use crate::rate_limit::TokenRateLimiter;
+use crate::token_resolver::InternalTokenResolver;
+use crate::token_verifier::InternalTokenVerifier;
+
+// RFC 8446 exporter label, the context tells apart the challenges of both sides
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-ratls-attestation";
+// Frames are prefixed with a 32-bit length, bigger ones are refused
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Server,
+    Client
+}
+
+impl Side {
+    fn context(&self) -> &'static [u8] {
+        match self {
+            Self::Server => b"server",
+            Self::Client => b"client"
+        }
+    }
+
+    fn peer(&self) -> Self {
+        match self {
+            Self::Server => Self::Client,
+            Self::Client => Self::Server
+        }
+    }
+}
+
+// Challenges derived from the TLS session, the own one is used for the local
+// evidence and the peer one is expected in the received evidence.
+#[derive(Clone)]
+pub(crate) struct ExporterChallenges {
+    own: [u8; 64],
+    peer: [u8; 64]
+}
+
+impl ExporterChallenges {
+    pub(crate) fn from_connection<S: SideData>(conn: &ConnectionCommon<S>, side: Side) -> Result<Self, RaTlsError> {
+        // Only the TLS 1.3 exporter is sure to be unique to the connection
+        if conn.protocol_version() != Some(ProtocolVersion::TLSv1_3) {
+            error!("Evidence can't be bound to a {:?} connection", conn.protocol_version());
+            return Err(RaTlsError::UnsupportedProtocolVersion);
+        }
+        Ok(Self {
+            own: conn.export_keying_material([0u8; 64], EXPORTER_LABEL, Some(side.context()))?,
+            peer: conn.export_keying_material([0u8; 64], EXPORTER_LABEL, Some(side.peer().context()))?
+        })
+    }
+}
+
+// Attestation run over an established TLS connection instead of inside the
+// handshake. Both sides send a single frame with their evidence, an empty one
+// if they are not attested, the server goes first.
+#[derive(Debug, Clone)]
+pub(crate) struct PostHandshake {
+    pub(crate) token_resolver: Option<Arc<dyn InternalTokenResolver>>,
+    pub(crate) evidence_encoding: EvidenceEncoding,
+    pub(crate) token_verifier: Option<Arc<dyn InternalTokenVerifier>>,
+    pub(crate) evidence_verifiers: Arc<EvidenceVerifiers>,
+    pub(crate) rate_limiter: Option<Arc<TokenRateLimiter>>
+}
+
+impl PostHandshake {
+    // The local token is recorded for the channel binding
+    fn evidence_frame(&self, challenges: &ExporterChallenges, source: Option<IpAddr>, tokens: &ExchangedTokens) -> Result<Vec<u8>, RaTlsError> {
+        let evidence = match &self.token_resolver {
+            None => Vec::new(),
+            Some(token_resolver) => {
+                let _permit = self.rate_limiter
+                    .as_ref()
+                    .map(|rate_limiter| rate_limiter.acquire(source))
+                    .transpose()?;
+                let token = token_resolver
+                    .resolve(&challenges.own)
+                    .inspect_err(|_| error!("Failed to acquire token from the token_resolver"))?;
+                let _ = tokens.local.set(token.clone());
+                cmw::wrap(token_resolver.media_type(), token, self.evidence_encoding)?
+            }
+        };
+
+        let mut frame = (evidence.len() as u32).to_be_bytes().to_vec();
+        frame.extend(evidence);
+        Ok(frame)
+    }
+
+    fn verify_evidence(
+        &self,
+        challenges: &ExporterChallenges,
+        evidence: &[u8],
+        tokens: &ExchangedTokens
+    ) -> Result<Option<Arc<PeerAttestation>>, RaTlsError> {
+        let Some(token_verifier) = &self.token_verifier else {
+            // Still a part of the channel binding on both ends
+            if !evidence.is_empty() {
+                let _ = tokens.peer.set(cmw::unwrap(evidence).value);
+            }
+            return Ok(None);
+        };
+
+        let verify = || {
+            if evidence.is_empty() {
+                return Err(RaTlsError::MissingPeerEvidence);
+            }
+            self.evidence_verifiers.verify_evidence(cmw::unwrap(evidence), &challenges.peer, token_verifier.as_ref())
+        };
+
+        match verify() {
+            Ok(attestation) => Ok(Some(Arc::new(attestation))),
+            Err(err) => {
+                let report = AttestationReport::from(&err);
+                error!("Attestation failed: {}", report);
+                Err(RaTlsError::AttestationFailed(report))
+            }
+        }
+    }
+
+    pub(crate) fn exchange<C, S>(&self, conn: &mut RaTlsConnection<C>, side: Side, deadline: Option<Instant>) -> Result<(), RaTlsError>
+    where
+        C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+        S: SideData
+    {
+        conn.complete_handshake(deadline)?;
+        let challenges = ExporterChallenges::from_connection(conn.connection(), side)?;
+        let source = conn.socket().peer_addr().ok().map(|addr| addr.ip());
+        let frame = self.evidence_frame(&challenges, source, conn.tokens())?;
+
+        let mut stream = conn.stream_until(deadline);
+        let evidence = match side {
+            Side::Server => {
+                stream.write_all(&frame)?;
+                stream.flush()?;
+                read_frame(&mut stream)?
+            },
+            Side::Client => {
+                let evidence = read_frame(&mut stream)?;
+                stream.write_all(&frame)?;
+                stream.flush()?;
+                evidence
+            }
+        };
+
+        if let Some(attestation) = self.verify_evidence(&challenges, &evidence, conn.tokens())? {
+            conn.set_peer_attestation(attestation);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn exchange_async<T>(
+        &self,
+        stream: &mut T,
+        challenges: ExporterChallenges,
+        side: Side,
+        source: Option<IpAddr>,
+        tokens: &Arc<ExchangedTokens>
+    ) -> Result<Option<Arc<PeerAttestation>>, RaTlsError>
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin
+    {
+        use tokio::io::AsyncWriteExt;
+
+        // The token resolver and the rate limiter block, keep them off the
+        // runtime workers
+        let frame = {
+            let (post_handshake, challenges, tokens) = (self.clone(), challenges.clone(), tokens.clone());
+            tokio::task::spawn_blocking(move || post_handshake.evidence_frame(&challenges, source, &tokens))
+                .await
+                .map_err(std::io::Error::other)??
+        };
+
+        let evidence = match side {
+            Side::Server => {
+                stream.write_all(&frame).await?;
+                stream.flush().await?;
+                read_frame_async(stream).await?
+            },
+            Side::Client => {
+                let evidence = read_frame_async(stream).await?;
+                stream.write_all(&frame).await?;
+                stream.flush().await?;
+                evidence
+            }
+        };
+
+        self.verify_evidence(&challenges, &evidence, tokens)
+    }
+}
+
+fn frame_len(len: [u8; 4]) -> Result<usize, RaTlsError> {
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        error!("Evidence frame of {} bytes is too big", len);
+        return Err(RaTlsError::InvalidAttestationFrame);
+    }
+    Ok(len)
+}
+
+fn read_frame(stream: &mut impl Read) -> Result<Vec<u8>, RaTlsError> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let mut evidence = vec![0u8; frame_len(len)?];
+    stream.read_exact(&mut evidence)?;
+    Ok(evidence)
+}
+
+#[cfg(feature = "tokio")]
+async fn read_frame_async(stream: &mut (impl tokio::io::AsyncRead + Unpin)) -> Result<Vec<u8>, RaTlsError> {
+    use tokio::io::AsyncReadExt;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len).await?;
+    let mut evidence = vec![0u8; frame_len(len)?];
+    stream.read_exact(&mut evidence).await?;
+    Ok(evidence)
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, net::{IpAddr, Ipv6Addr}, sync::{Condvar, Mutex}, time::{Duration, Instant}};
+use log::warn;
+use crate::error::RaTlsError;
+
+// Limits on the evidence generated for unauthenticated peers. Each request
+// takes a token from the global bucket and from the bucket of its source
+// address, then waits for one of the resolver slots. Requests that find an
+// empty bucket or a full queue, or that wait too long, are refused before the
+// token resolver runs. Requests without a known source address only take a
+// token from the global bucket. IPv6 sources are accounted per prefix, as a
+// single host usually gets a whole /64.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenRateLimit {
+    rate: f64,
+    burst: f64,
+    source_rate: f64,
+    source_burst: f64,
+    max_concurrent: usize,
+    max_queued: usize,
+    max_wait: Duration,
+    max_sources: usize,
+    ipv6_prefix: u8
+}
+
+impl Default for TokenRateLimit {
+    fn default() -> Self {
+        Self {
+            rate: 10.0,
+            burst: 20.0,
+            source_rate: 1.0,
+            source_burst: 5.0,
+            max_concurrent: 1,
+            max_queued: 16,
+            max_wait: Duration::from_secs(5),
+            max_sources: 4096,
+            ipv6_prefix: 64
+        }
+    }
+}
+
+impl TokenRateLimit {
+    // Tokens per second for all peers together
+    pub fn with_rate(mut self, per_second: f64, burst: u32) -> Self {
+        self.rate = per_second;
+        self.burst = burst.max(1) as f64;
+        self
+    }
+
+    // Tokens per second for a single source address
+    pub fn with_source_rate(mut self, per_second: f64, burst: u32) -> Self {
+        self.source_rate = per_second;
+        self.source_burst = burst.max(1) as f64;
+        self
+    }
+
+    // Resolver calls running at once and requests allowed to wait for them
+    pub fn with_queue(mut self, max_concurrent: usize, max_queued: usize) -> Self {
+        self.max_concurrent = max_concurrent.max(1);
+        self.max_queued = max_queued;
+        self
+    }
+
+    // How long a queued request waits for a resolver slot
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    // Sources tracked at once. Once the table is full, sources with a full
+    // bucket are forgotten first, then the least recently used ones until a
+    // quarter of the table is free.
+    pub fn with_max_sources(mut self, max_sources: usize) -> Self {
+        self.max_sources = max_sources.max(1);
+        self
+    }
+
+    // Length of the prefix identifying an IPv6 source, 64 by default
+    pub fn with_ipv6_prefix(mut self, prefix_len: u8) -> Self {
+        self.ipv6_prefix = prefix_len.min(128);
+        self
+    }
+
+    fn source(&self, addr: IpAddr) -> IpAddr {
+        match addr.to_canonical() {
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.ipv6_prefix)).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            },
+            addr => addr
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+impl Bucket {
+    fn full(burst: f64, now: Instant) -> Self {
+        Self { tokens: burst, updated: now }
+    }
+
+    // Refilled up to the burst by now, no different from a new bucket
+    fn is_full(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        self.tokens + now.saturating_duration_since(self.updated).as_secs_f64() * rate >= burst
+    }
+
+    // Buckets are only refilled when used, so updated is the time of the
+    // last use
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    global: Bucket,
+    sources: HashMap<IpAddr, Bucket>
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    running: usize,
+    waiting: usize
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenRateLimiter {
+    limit: TokenRateLimit,
+    buckets: Mutex<Buckets>,
+    queue: Mutex<Queue>,
+    slot_freed: Condvar
+}
+
+// Holds a resolver slot, released on drop
+pub(crate) struct TokenPermit<'a> {
+    limiter: &'a TokenRateLimiter
+}
+
+impl TokenRateLimiter {
+    pub(crate) fn new(limit: TokenRateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(Buckets { global: Bucket::full(limit.burst, Instant::now()), sources: HashMap::new() }),
+            queue: Mutex::new(Queue::default()),
+            slot_freed: Condvar::new()
+        }
+    }
+
+    pub(crate) fn acquire(&self, source: Option<IpAddr>) -> Result<TokenPermit<'_>, RaTlsError> {
+        self.take_token(source)?;
+        self.take_slot()
+    }
+
+    pub(crate) fn take_token(&self, source: Option<IpAddr>) -> Result<(), RaTlsError> {
+        let limit = &self.limit;
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().map_err(|_| RaTlsError::RateLimited)?;
+
+        buckets.global.refill(limit.rate, limit.burst, now);
+        if buckets.global.tokens < 1.0 {
+            warn!("Token rate limit exceeded");
+            return Err(RaTlsError::RateLimited);
+        }
+
+        if let Some(source) = source.map(|addr| limit.source(addr)) {
+            if !buckets.sources.contains_key(&source) && buckets.sources.len() >= limit.max_sources {
+                make_room(&mut buckets.sources, limit, now);
+            }
+
+            let bucket = buckets.sources
+                .entry(source)
+                .or_insert_with(|| Bucket::full(limit.source_burst, now));
+            bucket.refill(limit.source_rate, limit.source_burst, now);
+            if bucket.tokens < 1.0 {
+                warn!("Token rate limit exceeded by {}", source);
+                return Err(RaTlsError::RateLimited);
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        buckets.global.tokens -= 1.0;
+        Ok(())
+    }
+
+    pub(crate) fn take_slot(&self) -> Result<TokenPermit<'_>, RaTlsError> {
+        let mut queue = self.queue.lock().map_err(|_| RaTlsError::RateLimited)?;
+
+        if queue.running >= self.limit.max_concurrent {
+            if queue.waiting >= self.limit.max_queued {
+                warn!("Token request queue is full");
+                return Err(RaTlsError::RateLimited);
+            }
+            queue.waiting += 1;
+            let deadline = Instant::now() + self.limit.max_wait;
+            while queue.running >= self.limit.max_concurrent {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    queue.waiting -= 1;
+                    warn!("Timed out waiting for a token request slot");
+                    return Err(RaTlsError::RateLimited);
+                }
+                queue = self.slot_freed.wait_timeout(queue, remaining).map_err(|_| RaTlsError::RateLimited)?.0;
+            }
+            queue.waiting -= 1;
+        }
+
+        queue.running += 1;
+        Ok(TokenPermit { limiter: self })
+    }
+}
+
+// Refusing new sources would let a full table lock out every new client, so
+// sources are forgotten instead. Freeing a quarter of the table at once keeps
+// the cost of the sweeps amortised over the insertions that follow.
+fn make_room(sources: &mut HashMap<IpAddr, Bucket>, limit: &TokenRateLimit, now: Instant) {
+    sources.retain(|_, bucket| !bucket.is_full(limit.source_rate, limit.source_burst, now));
+
+    let keep = limit.max_sources - limit.max_sources.div_ceil(4);
+    if sources.len() <= keep {
+        return;
+    }
+    let excess = sources.len() - keep;
+    let mut used = sources.values().map(|bucket| bucket.updated).collect::<Vec<_>>();
+    let cutoff = *used.select_nth_unstable(excess - 1).1;
+
+    let mut forgotten = 0;
+    sources.retain(|_, bucket| {
+        let forget = forgotten < excess && bucket.updated <= cutoff;
+        forgotten += usize::from(forget);
+        !forget
+    });
+    warn!("Too many sources requesting tokens, forgot {} least recently used", forgotten);
+}
+
+impl Drop for TokenPermit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut queue) = self.limiter.queue.lock() {
+            queue.running -= 1;
+        }
+        self.limiter.slot_freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn new_source_evicts_least_recently_used() {
+        let limiter = TokenRateLimiter::new(TokenRateLimit::default().with_source_rate(0.0, 5).with_max_sources(4));
+        let source = |last| IpAddr::from([192, 0, 2, last]);
+
+        // The buckets are left partly drained and never refill
+        for last in [1, 2, 3, 4, 1] {
+            limiter.take_token(Some(source(last))).unwrap();
+        }
+
+        assert!(limiter.take_token(Some(source(5))).is_ok());
+        let buckets = limiter.buckets.lock().unwrap();
+        let mut tracked = buckets.sources.keys().copied().collect::<Vec<_>>();
+        tracked.sort();
+        assert_eq!(tracked, [1, 3, 4, 5].map(source));
+    }
+
+    #[test]
+    fn ipv6_sources_are_accounted_per_prefix() {
+        let limit = TokenRateLimit::default().with_source_rate(0.0, 1);
+        let host = |network: u16, interface: u16| IpAddr::from([0x2001, 0xdb8, 0, network, 0, 0, 0, interface]);
+
+        let limiter = TokenRateLimiter::new(limit);
+        limiter.take_token(Some(host(1, 1))).unwrap();
+        assert!(matches!(limiter.take_token(Some(host(1, 2))), Err(RaTlsError::RateLimited)));
+        assert!(limiter.take_token(Some(host(2, 1))).is_ok());
+
+        let limiter = TokenRateLimiter::new(limit.with_ipv6_prefix(128));
+        limiter.take_token(Some(host(1, 1))).unwrap();
+        assert!(limiter.take_token(Some(host(1, 2))).is_ok());
+    }
+
+    #[test]
+    fn full_queue_is_refused() {
+        let limiter = TokenRateLimiter::new(TokenRateLimit::default().with_queue(1, 0));
+
+        let _permit = limiter.take_slot().unwrap();
+        assert!(matches!(limiter.take_slot(), Err(RaTlsError::RateLimited)));
+    }
+
+    #[test]
+    fn queued_request_times_out() {
+        let max_wait = Duration::from_millis(50);
+        let limiter = TokenRateLimiter::new(TokenRateLimit::default().with_queue(1, 1).with_max_wait(max_wait));
+
+        let _permit = limiter.take_slot().unwrap();
+        let started = Instant::now();
+        assert!(matches!(limiter.take_slot(), Err(RaTlsError::RateLimited)));
+        assert!(started.elapsed() >= max_wait);
+
+        // The timed out request no longer counts as queued
+        assert_eq!(limiter.queue.lock().unwrap().waiting, 0);
+    }
+
+    #[test]
+    fn queued_request_gets_freed_slot() {
+        let limiter = TokenRateLimiter::new(TokenRateLimit::default().with_queue(1, 1));
+
+        let permit = limiter.take_slot().unwrap();
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| limiter.take_slot().map(drop));
+            thread::sleep(Duration::from_millis(20));
+            drop(permit);
+            assert!(waiter.join().unwrap().is_ok());
+        });
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, io, net::{Shutdown, SocketAddr, TcpListener, TcpStream}, panic::{self, AssertUnwindSafe}, thread};
+use std::sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc, Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use log::{error, warn};
+use rustls::ServerConnection;
+use crate::{connection::RaTlsConnection, error::RaTlsError, server::{establish, ServerConfigFactory}};
+
+// How often the acceptor checks for shutdown while no client is connecting
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub(crate) type ConnectionHandler = Arc<dyn Fn(RaTlsConnection<ServerConnection>) + Send + Sync>;
+pub(crate) type ErrorHandler = Arc<dyn Fn(Option<SocketAddr>, RaTlsError) + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    workers: usize,
+    max_handshakes: usize,
+    max_connections: usize,
+    handshake_timeout: Duration,
+    read_timeout: Option<Duration>,
+    drain_timeout: Duration
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            max_handshakes: 64,
+            max_connections: 256,
+            handshake_timeout: Duration::from_secs(10),
+            read_timeout: Some(Duration::from_secs(30)),
+            drain_timeout: Duration::from_secs(30)
+        }
+    }
+}
+
+impl ServeOptions {
+    // Threads running the handshakes, the handlers run on threads of their own
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    // Connections accepted but not attested yet, queued ones included. New
+    // clients above the limit are closed right away and reported as ServerBusy.
+    pub fn with_max_handshakes(mut self, max_handshakes: usize) -> Self {
+        self.max_handshakes = max_handshakes.max(1);
+        self
+    }
+
+    // Open connections, handshaking or handled. New clients above the limit
+    // are closed right away and reported as ServerBusy.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    // Time from accept until the handshake and the attestation are done
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    // Applied to every read and write once the connection is handed over to
+    // the handler, None blocks forever.
+    pub fn with_read_timeout(mut self, read_timeout: Option<Duration>) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    // Time the handlers get to finish after shutdown, then their sockets are
+    // closed.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+}
+
+// Can be cloned and sent to other threads, e.g. a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownSignal(Arc<AtomicBool>);
+
+impl ShutdownSignal {
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+#[derive(Debug, Default)]
+struct OpenConnections {
+    count: usize,
+    next_id: u64,
+    handled: HashMap<u64, TcpStream>
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    open: Mutex<OpenConnections>,
+    closed: Condvar
+}
+
+impl Connections {
+    fn reserve(&self, max_connections: usize) -> bool {
+        let Ok(mut open) = self.open.lock() else {
+            return false;
+        };
+        if open.count >= max_connections {
+            return false;
+        }
+        open.count += 1;
+        true
+    }
+
+    fn release(&self, id: Option<u64>) {
+        if let Ok(mut open) = self.open.lock() {
+            open.count -= 1;
+            if let Some(id) = id {
+                open.handled.remove(&id);
+            }
+        }
+        self.closed.notify_all();
+    }
+
+    fn register(&self, sock: &TcpStream) -> Result<u64, RaTlsError> {
+        let sock = sock.try_clone()?;
+        let mut open = self.open.lock().map_err(|_| RaTlsError::ServerBusy)?;
+        let id = open.next_id;
+        open.next_id += 1;
+        open.handled.insert(id, sock);
+        Ok(id)
+    }
+
+    // Returns whether every connection was closed before the deadline
+    fn wait_closed(&self, deadline: Instant) -> bool {
+        let Ok(mut open) = self.open.lock() else {
+            return false;
+        };
+        while open.count > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            open = match self.closed.wait_timeout(open, remaining) {
+                Ok((open, _)) => open,
+                Err(_) => return false
+            };
+        }
+        true
+    }
+
+    fn close_all(&self) {
+        if let Ok(open) = self.open.lock() {
+            for sock in open.handled.values() {
+                let _ = sock.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+// Releases the connection when the handler returns or panics
+struct HandledConnection {
+    connections: Arc<Connections>,
+    id: u64
+}
+
+impl Drop for HandledConnection {
+    fn drop(&mut self) {
+        self.connections.release(Some(self.id));
+    }
+}
+
+// Returned by RaTlsServer::serve. After shutdown no new connections are
+// accepted, join waits until the queued and running ones are done.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    signal: ShutdownSignal,
+    threads: Vec<thread::JoinHandle<()>>,
+    connections: Arc<Connections>,
+    drain_timeout: Duration
+}
+
+impl ServerHandle {
+    pub(crate) fn start(
+        config_factory: ServerConfigFactory,
+        listener: TcpListener,
+        options: ServeOptions,
+        handler: ConnectionHandler,
+        on_error: ErrorHandler
+    ) -> Result<Self, RaTlsError> {
+        let local_addr = listener.local_addr()?;
+        listener.set_nonblocking(true)?;
+
+        let signal = ShutdownSignal::default();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(Connections::default());
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let mut threads = Vec::with_capacity(options.workers + 1);
+
+        for id in 0..options.workers {
+            let worker = Worker {
+                config_factory: config_factory.clone(),
+                options: options.clone(),
+                pending: pending.clone(),
+                connections: connections.clone(),
+                handler: handler.clone(),
+                on_error: on_error.clone()
+            };
+            let receiver = receiver.clone();
+            threads.push(thread::Builder::new()
+                .name(format!("ratls-worker-{id}"))
+                .spawn(move || worker.run(receiver))?);
+        }
+
+        let acceptor = Acceptor {
+            listener,
+            signal: signal.clone(),
+            max_handshakes: options.max_handshakes,
+            max_connections: options.max_connections,
+            pending,
+            connections: connections.clone(),
+            on_error
+        };
+        threads.push(thread::Builder::new()
+            .name("ratls-acceptor".to_owned())
+            .spawn(move || acceptor.run(sender))?);
+
+        Ok(Self { local_addr, signal, threads, connections, drain_timeout: options.drain_timeout })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn signal(&self) -> ShutdownSignal {
+        self.signal.clone()
+    }
+
+    pub fn shutdown(self) {
+        self.signal.trigger();
+        self.join();
+    }
+
+    // Returns once the server was shut down and every connection is closed.
+    pub fn join(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+
+        if !self.connections.wait_closed(Instant::now() + self.drain_timeout) {
+            warn!("Closing the connections still open after the drain timeout");
+            self.connections.close_all();
+            self.connections.wait_closed(Instant::now() + self.drain_timeout);
+        }
+    }
+}
+
+type Accepted = (TcpStream, SocketAddr, Instant);
+
+struct Acceptor {
+    listener: TcpListener,
+    signal: ShutdownSignal,
+    max_handshakes: usize,
+    max_connections: usize,
+    pending: Arc<AtomicUsize>,
+    connections: Arc<Connections>,
+    on_error: ErrorHandler
+}
+
+impl Acceptor {
+    // Dropping the sender on return lets the workers finish the queue and exit.
+    fn run(self, sender: mpsc::Sender<Accepted>) {
+        while !self.signal.is_triggered() {
+            match self.listener.accept() {
+                Ok((sock, addr)) => {
+                    if self.pending.load(Ordering::Acquire) >= self.max_handshakes {
+                        (self.on_error)(Some(addr), RaTlsError::ServerBusy);
+                        continue;
+                    }
+                    if !self.connections.reserve(self.max_connections) {
+                        (self.on_error)(Some(addr), RaTlsError::ServerBusy);
+                        continue;
+                    }
+                    self.pending.fetch_add(1, Ordering::AcqRel);
+                    if sender.send((sock, addr, Instant::now())).is_err() {
+                        break;
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                Err(err) => {
+                    // e.g. out of file descriptors, retry later
+                    (self.on_error)(None, err.into());
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+            }
+        }
+    }
+}
+
+struct Worker {
+    config_factory: ServerConfigFactory,
+    options: ServeOptions,
+    pending: Arc<AtomicUsize>,
+    connections: Arc<Connections>,
+    handler: ConnectionHandler,
+    on_error: ErrorHandler
+}
+
+impl Worker {
+    fn run(self, receiver: Arc<Mutex<mpsc::Receiver<Accepted>>>) {
+        loop {
+            let accepted = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return
+            };
+            let Ok((sock, addr, accepted_at)) = accepted else {
+                return;
+            };
+
+            let result = self.establish(sock, accepted_at + self.options.handshake_timeout);
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+
+            let result = match result {
+                Ok(conn) => self.spawn_handler(conn, addr),
+                Err(err) => {
+                    self.connections.release(None);
+                    Err(err)
+                }
+            };
+            if let Err(err) = result {
+                (self.on_error)(Some(addr), err);
+            }
+        }
+    }
+
+    // The connection is released when the handler is done, or right away if
+    // it can't be started
+    fn spawn_handler(&self, conn: RaTlsConnection<ServerConnection>, addr: SocketAddr) -> Result<(), RaTlsError> {
+        let id = self.connections.register(conn.socket()).inspect_err(|_| self.connections.release(None))?;
+        let handled = HandledConnection { connections: self.connections.clone(), id };
+        let handler = self.handler.clone();
+
+        thread::Builder::new()
+            .name(format!("ratls-conn-{addr}"))
+            .spawn(move || {
+                let _handled = handled;
+                if panic::catch_unwind(AssertUnwindSafe(|| handler(conn))).is_err() {
+                    error!("Connection handler for {} panicked", addr);
+                }
+            })?;
+        Ok(())
+    }
+
+    fn establish(&self, sock: TcpStream, deadline: Instant) -> Result<RaTlsConnection<ServerConnection>, RaTlsError> {
+        // Some platforms hand out sockets inheriting the non-blocking listener mode
+        sock.set_nonblocking(false)?;
+        let conn = establish(&self.config_factory, sock, Some(deadline))?;
+
+        conn.socket().set_read_timeout(self.options.read_timeout)?;
+        conn.socket().set_write_timeout(self.options.read_timeout)?;
+        Ok(conn)
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use ciborium::Value;
+use ring::{rand::SystemRandom, signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519}};
+use crate::{error::RaTlsError, tools::read_file};
+use crate::evidence::{EvidenceClaims, EvidenceVerifier, VerifiedEvidence};
+use crate::token_resolver::InternalTokenResolver;
+
+// Software only evidence format, useful for testing relying parties without
+// a TEE. The evidence is a CBOR array [payload, signature] where the payload
+// is a CBOR map {"challenge": bstr, "measurement": bstr, "key": bstr} signed
+// with the Ed25519 key included in it.
+pub const SAMPLE_MEDIA_TYPE: &str = "application/vnd.ratls.sample-evidence+cbor";
+
+#[derive(Debug)]
+pub struct SampleEvidenceResolver {
+    key_pair: Ed25519KeyPair,
+    measurement: Vec<u8>
+}
+
+impl SampleEvidenceResolver {
+    pub fn generate(measurement: Vec<u8>) -> Result<Self, RaTlsError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| RaTlsError::PrivateKeyParsingError("Failed to generate Ed25519 key".to_owned()))?;
+        Self::from_pkcs8(pkcs8.as_ref(), measurement)
+    }
+
+    pub fn from_pkcs8(pkcs8: &[u8], measurement: Vec<u8>) -> Result<Self, RaTlsError> {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|e| RaTlsError::PrivateKeyParsingError(e.to_string()))?;
+        Ok(Self { key_pair, measurement })
+    }
+
+    pub fn from_path(path: impl AsRef<str>, measurement: Vec<u8>) -> Result<Self, RaTlsError> {
+        Self::from_pkcs8(&read_file(path)?, measurement)
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+}
+
+impl InternalTokenResolver for SampleEvidenceResolver {
+    fn resolve(&self, challenge: &[u8]) -> Result<Vec<u8>, RaTlsError> {
+        let payload = Value::Map(vec![
+            (Value::Text("challenge".to_owned()), Value::Bytes(challenge.to_vec())),
+            (Value::Text("measurement".to_owned()), Value::Bytes(self.measurement.clone())),
+            (Value::Text("key".to_owned()), Value::Bytes(self.public_key().to_vec()))
+        ]);
+        let mut encoded_payload = Vec::new();
+        ciborium::into_writer(&payload, &mut encoded_payload)?;
+
+        let signature = self.key_pair.sign(&encoded_payload);
+        let evidence = Value::Array(vec![
+            Value::Bytes(encoded_payload),
+            Value::Bytes(signature.as_ref().to_vec())
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&evidence, &mut encoded)?;
+
+        Ok(encoded)
+    }
+
+    fn media_type(&self) -> &str {
+        SAMPLE_MEDIA_TYPE
+    }
+}
+
+// Accepts the sample evidence signed with one of the trusted keys.
+#[derive(Debug)]
+pub struct SampleEvidenceVerifier {
+    trusted_keys: Vec<Vec<u8>>
+}
+
+impl SampleEvidenceVerifier {
+    pub fn new(trusted_keys: Vec<Vec<u8>>) -> Self {
+        Self { trusted_keys }
+    }
+}
+
+impl EvidenceVerifier for SampleEvidenceVerifier {
+    fn media_type(&self) -> &str {
+        SAMPLE_MEDIA_TYPE
+    }
+
+    fn verify(&self, evidence: &[u8]) -> Result<VerifiedEvidence, RaTlsError> {
+        let (payload, signature) = match ciborium::from_reader(evidence)? {
+            Value::Array(items) => match items.as_slice() {
+                [Value::Bytes(payload), Value::Bytes(signature)] => (payload.clone(), signature.clone()),
+                _ => return Err(RaTlsError::InvalidSampleEvidence)
+            },
+            _ => return Err(RaTlsError::InvalidSampleEvidence)
+        };
+
+        let mut claims = BTreeMap::new();
+        match ciborium::from_reader(payload.as_slice())? {
+            Value::Map(entries) => {
+                for (label, value) in entries {
+                    if let (Value::Text(label), Value::Bytes(value)) = (label, value) {
+                        claims.insert(label, value);
+                    }
+                }
+            },
+            _ => return Err(RaTlsError::InvalidSampleEvidence)
+        }
+
+        let (Some(challenge), Some(measurement), Some(key)) =
+            (claims.get("challenge"), claims.get("measurement"), claims.get("key")) else {
+            return Err(RaTlsError::InvalidSampleEvidence);
+        };
+
+        if !self.trusted_keys.contains(key) {
+            return Err(RaTlsError::UntrustedEvidenceKey);
+        }
+
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(&payload, &signature)
+            .map_err(|_| RaTlsError::InvalidEvidenceSignature)?;
+
+        Ok(VerifiedEvidence {
+            challenge: challenge.clone(),
+            identity: measurement.clone(),
+            claims: EvidenceClaims::Generic(claims)
+        })
+    }
+}
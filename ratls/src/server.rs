@@ -1,7 +1,11 @@
-use std::{net::TcpListener, sync::Arc};
-use rustls::{ServerConfig, ServerConnection};
-use crate::{cert_resolver::RaTlsCertResolver, cert_verifier::RaTlsCertVeryfier, error::RaTlsError, tools::{self, load_certificates_from_pem, load_private_key_from_file}};
-use crate::connection::RaTlsConnection;
+use std::{net::{IpAddr, SocketAddr, TcpListener, TcpStream}, sync::Arc, time::Instant};
+use rustls::{server::{danger::ClientCertVerifier, ResolvesServerCert}, version::TLS13, RootCertStore, ServerConfig, ServerConnection};
+use crate::{builder::RaTlsServerBuilder, cert_resolver::RaTlsCertResolver, cert_verifier::RaTlsCertVeryfier, error::RaTlsError};
+use crate::{config::{RaTlsProtocol, HANDSHAKE_MSG}, connection::RaTlsConnection};
+use crate::channel_binding::ExchangedTokens;
+use crate::evidence::EvidenceVerifiers;
+use crate::post_handshake::{PostHandshake, Side};
+use crate::runner::{ServeOptions, ServerHandle};
 use crate::token_resolver::InternalTokenResolver;
 use crate::token_verifier::InternalTokenVerifier;
 use std::io::{Read, Write};
@@ -21,40 +25,209 @@ pub enum ServerMode {
     }
 }
 
+#[derive(Debug, Clone)]
+pub(crate) enum ServerCertificate {
+    Fixed(Arc<dyn ResolvesServerCert>),
+    // Created for every connection, the token requests are accounted per peer
+    Attested(Arc<RaTlsCertResolver>)
+}
+
+impl ServerCertificate {
+    fn resolver(&self, peer: Option<IpAddr>, tokens: Arc<ExchangedTokens>) -> Arc<dyn ResolvesServerCert> {
+        match self {
+            Self::Fixed(resolver) => resolver.clone(),
+            Self::Attested(resolver) => resolver.for_connection(peer, tokens)
+        }
+    }
+
+    // Same as resolver, with the certificate created right away
+    #[cfg(feature = "tokio")]
+    fn resolved(&self, server_name: Option<&str>, peer: Option<IpAddr>, tokens: Arc<ExchangedTokens>) -> Arc<dyn ResolvesServerCert> {
+        match self {
+            Self::Fixed(resolver) => resolver.clone(),
+            Self::Attested(resolver) => resolver.for_connection(peer, tokens).resolve_now(server_name)
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    fn batches_tokens(&self) -> bool {
+        matches!(self, Self::Attested(resolver) if resolver.batches_tokens())
+    }
+}
+
+// Holds everything that can be shared between connections. The client
+// verifier carries the attestation challenge so it is created anew for
+// every ServerConfig, one per accepted connection. Clients with regular
+// certificates are checked by the client_cert_verifier, if any.
+#[derive(Debug, Clone)]
+pub struct ServerConfigFactory {
+    client_token_verifier: Option<Arc<dyn InternalTokenVerifier>>,
+    evidence_verifiers: Arc<EvidenceVerifiers>,
+    client_cert_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    client_chain_anchors: Option<Arc<RootCertStore>>,
+    cert_resolver: ServerCertificate,
+    post_handshake: Option<PostHandshake>,
+    protocol: RaTlsProtocol
+}
+
+impl ServerConfigFactory {
+    pub(crate) fn new(
+        client_token_verifier: Option<Arc<dyn InternalTokenVerifier>>,
+        evidence_verifiers: Arc<EvidenceVerifiers>,
+        client_cert_verifier: Option<Arc<dyn ClientCertVerifier>>,
+        client_chain_anchors: Option<Arc<RootCertStore>>,
+        cert_resolver: ServerCertificate,
+        post_handshake: Option<PostHandshake>,
+        protocol: RaTlsProtocol
+    ) -> Self {
+        Self {
+            client_token_verifier,
+            evidence_verifiers,
+            client_cert_verifier,
+            client_chain_anchors,
+            cert_resolver,
+            post_handshake,
+            protocol
+        }
+    }
+
+    pub(crate) fn protocol(&self) -> &RaTlsProtocol {
+        &self.protocol
+    }
+
+    // Batched handshakes wait for each other inside the certificate resolver
+    #[cfg(feature = "tokio")]
+    pub(crate) fn batches_tokens(&self) -> bool {
+        self.cert_resolver.batches_tokens()
+    }
+
+    // Set if the evidence is exchanged after the TLS handshake
+    pub(crate) fn post_handshake(&self) -> Option<&PostHandshake> {
+        self.post_handshake.as_ref()
+    }
+
+    // The returned config must be used for a single connection only, the
+    // verifier gives access to the client attestation once it is verified.
+    // The token rate limit is applied with the global bucket only.
+    pub fn make_config(&self) -> (ServerConfig, Option<Arc<RaTlsCertVeryfier>>) {
+        let (config, verifier, _) = self.config_for(None);
+        (config, verifier)
+    }
+
+    // Same as make_config, the token rate limit is also applied per peer.
+    pub fn make_config_for_peer(&self, peer: IpAddr) -> (ServerConfig, Option<Arc<RaTlsCertVeryfier>>) {
+        let (config, verifier, _) = self.config_for(Some(peer));
+        (config, verifier)
+    }
+
+    // The tokens are recorded for the channel binding of the connection
+    pub(crate) fn config_for(&self, peer: Option<IpAddr>) -> (ServerConfig, Option<Arc<RaTlsCertVeryfier>>, Arc<ExchangedTokens>) {
+        let tokens = Arc::new(ExchangedTokens::default());
+        self.config_with(self.cert_resolver.resolver(peer, tokens.clone()), tokens)
+    }
+
+    // Same as config_for with the certificate already created for the server
+    // name of the ClientHello, blocks in the token resolver.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn config_for_hello(
+        &self,
+        server_name: Option<&str>,
+        peer: Option<IpAddr>
+    ) -> (ServerConfig, Option<Arc<RaTlsCertVeryfier>>, Arc<ExchangedTokens>) {
+        let tokens = Arc::new(ExchangedTokens::default());
+        self.config_with(self.cert_resolver.resolved(server_name, peer, tokens.clone()), tokens)
+    }
+
+    fn config_with(
+        &self,
+        cert_resolver: Arc<dyn ResolvesServerCert>,
+        tokens: Arc<ExchangedTokens>
+    ) -> (ServerConfig, Option<Arc<RaTlsCertVeryfier>>, Arc<ExchangedTokens>) {
+        // The exporter is unique to the connection only with TLS 1.3, rustls
+        // can't tell if a TLS 1.2 session used the extended master secret.
+        let builder = match self.post_handshake {
+            Some(_) => ServerConfig::builder_with_protocol_versions(&[&TLS13]),
+            None => ServerConfig::builder()
+        };
+        let verifier = self.client_token_verifier
+            .as_ref()
+            .map(|verifier| {
+                let mut verifier = RaTlsCertVeryfier::with_evidence_verifiers(verifier.clone(), self.evidence_verifiers.clone());
+                if let Some(client_chain_anchors) = &self.client_chain_anchors {
+                    verifier = verifier.with_chain_anchors(client_chain_anchors.clone());
+                }
+                Arc::new(match &self.client_cert_verifier {
+                    Some(client_cert_verifier) => verifier.with_client_cert_verifier(client_cert_verifier.clone()),
+                    None => verifier
+                })
+            });
+        let builder = match (&verifier, &self.client_cert_verifier) {
+            (Some(verifier), _) => builder.with_client_cert_verifier(verifier.clone()),
+            (None, Some(client_cert_verifier)) => builder.with_client_cert_verifier(client_cert_verifier.clone()),
+            (None, None) => builder.with_no_client_auth()
+        };
+
+        let mut config = builder.with_cert_resolver(cert_resolver);
+        // Applications may add their own protocols after this one
+        config.alpn_protocols = self.protocol.alpn_protocols();
+
+        (config, verifier, tokens)
+    }
+}
+
 pub struct RaTlsConnectionsIterator {
-    config: Arc<ServerConfig>,
+    config_factory: ServerConfigFactory,
     listener: TcpListener
 }
 
 impl RaTlsConnectionsIterator {
-    pub fn new(config: Arc<ServerConfig>, listener: TcpListener) -> Self {
-        Self { config, listener }
+    pub(crate) fn new(config_factory: ServerConfigFactory, listener: TcpListener) -> Self {
+        Self { config_factory, listener }
     }
 
     fn accept_connection(&self) -> Result<RaTlsConnection<ServerConnection>, RaTlsError> {
-        let conn = ServerConnection::new(self.config.clone())?;
         let sock = self.listener.accept()?.0;
+        establish(&self.config_factory, sock, None)
+    }
+}
+
+// Runs the TLS handshake and the attestation steps that follow it on an
+// accepted socket.
+pub(crate) fn establish(
+    config_factory: &ServerConfigFactory,
+    sock: TcpStream,
+    deadline: Option<Instant>
+) -> Result<RaTlsConnection<ServerConnection>, RaTlsError> {
+    let peer = sock.peer_addr()?.ip();
+    let (config, verifier, tokens) = config_factory.config_for(Some(peer));
+    let conn = ServerConnection::new(Arc::new(config))?;
 
-        let mut tlsconn = RaTlsConnection::new(sock, conn);
-        self.handshake(&mut tlsconn)?;
-        Ok(tlsconn)
+    let mut tlsconn = RaTlsConnection::with_verifier(sock, conn, verifier, tokens);
+    tlsconn.complete_handshake(deadline)?;
+    let legacy_handshake = config_factory.protocol().check(tlsconn.connection().alpn_protocol())?;
+
+    match config_factory.post_handshake() {
+        Some(post_handshake) => post_handshake.exchange(&mut tlsconn, Side::Server, deadline)?,
+        None if legacy_handshake => handshake(&mut tlsconn, deadline)?,
+        None => ()
     }
+    Ok(tlsconn)
+}
 
-    fn handshake(&self, conn: &mut RaTlsConnection<ServerConnection>) -> Result<(), RaTlsError> {
-        let mut stream = conn.stream();
-        let msg = "HELO";
+fn handshake(conn: &mut RaTlsConnection<ServerConnection>, deadline: Option<Instant>) -> Result<(), RaTlsError> {
+    let mut stream = conn.stream_until(deadline);
+    let msg = HANDSHAKE_MSG;
 
-        stream.write_all(msg.as_bytes())?;
-        stream.flush()?;
+    stream.write_all(msg.as_bytes())?;
+    stream.flush()?;
 
-        let mut resp = vec![0; msg.len()];
-        stream.read_exact(&mut resp)?;
+    let mut resp = vec![0; msg.len()];
+    stream.read_exact(&mut resp)?;
 
-        if resp.as_slice() == msg.as_bytes() {
-            Ok(())
-        } else {
-            Err(RaTlsError::HandshakeError)
-        }
+    if resp.as_slice() == msg.as_bytes() {
+        Ok(())
+    } else {
+        Err(RaTlsError::HandshakeError)
     }
 }
 
@@ -67,45 +240,46 @@ impl Iterator for RaTlsConnectionsIterator {
 }
 
 pub struct RaTlsServer {
-    mode: ServerMode
+    config_factory: ServerConfigFactory
 }
 
 impl RaTlsServer {
     pub fn new(mode: ServerMode) -> Result<Self, RaTlsError> {
-        Ok(Self { mode })
-    }
-
-    fn make_server_config(&self) -> Result<ServerConfig, RaTlsError> {
-        tools::install_default_crypto_provider();
-        match &self.mode {
-            ServerMode::AttestedClient { client_token_verifier, server_certificate_path, server_privatekey_path } => {
-                Ok(ServerConfig::builder()
-                    .with_client_cert_verifier(Arc::new(RaTlsCertVeryfier::from_token_verifier(client_token_verifier.clone())))
-                    .with_single_cert(
-                        load_certificates_from_pem(server_certificate_path)?,
-                        load_private_key_from_file(server_privatekey_path)?
-                    )?
-                )
-            },
-            ServerMode::AttestedServer { server_token_resolver } => {
-                Ok(ServerConfig::builder()
-                    .with_no_client_auth()
-                    .with_cert_resolver(Arc::new(RaTlsCertResolver::from_token_resolver(server_token_resolver.clone())?))
-                )
-            },
-            ServerMode::MutualAttestation { client_token_verifier, server_token_resolver } => {
-                Ok(ServerConfig::builder()
-                    .with_client_cert_verifier(Arc::new(RaTlsCertVeryfier::from_token_verifier(client_token_verifier.clone())))
-                    .with_cert_resolver(Arc::new(RaTlsCertResolver::from_token_resolver(server_token_resolver.clone())?))
-                )
-            }
-        }
+        RaTlsServerBuilder::from_mode(mode)?.build()
+    }
+
+    pub fn from_config_factory(config_factory: ServerConfigFactory) -> Self {
+        Self { config_factory }
     }
 
     pub fn connections(&self, bind_address: impl AsRef<str>) -> Result<RaTlsConnectionsIterator, RaTlsError> {
         Ok(RaTlsConnectionsIterator::new(
-            Arc::new(self.make_server_config()?),
+            self.config_factory.clone(),
             TcpListener::bind(bind_address.as_ref())?
         ))
     }
+
+    // Accepts connections on a background thread and hands them to a pool of
+    // workers. The handler runs on a thread of its own once the peer is
+    // attested, failed handshakes are passed to on_error together with the
+    // peer address.
+    pub fn serve<H, E>(
+        &self,
+        bind_address: impl AsRef<str>,
+        options: ServeOptions,
+        handler: H,
+        on_error: E
+    ) -> Result<ServerHandle, RaTlsError>
+    where
+        H: Fn(RaTlsConnection<ServerConnection>) + Send + Sync + 'static,
+        E: Fn(Option<SocketAddr>, RaTlsError) + Send + Sync + 'static
+    {
+        ServerHandle::start(
+            self.config_factory.clone(),
+            TcpListener::bind(bind_address.as_ref())?,
+            options,
+            Arc::new(handler),
+            Arc::new(on_error)
+        )
+    }
 }
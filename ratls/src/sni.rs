@@ -0,0 +1,103 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use rustls::pki_types::DnsName;
+use crate::error::RaTlsError;
+
+// The challenge travels in the SNI as lowercase base32 split into labels
+// followed by a reserved label and the real hostname of the server:
+//
+//   <base32 part>.<base32 part>.ratls-nonce.<hostname>
+//
+// so the name stays a valid DNS name and can still be used for routing.
+// An IP address is not a valid DNS name, it is sent as a single label made
+// of a reserved prefix and its octets in hex, e.g. ratls-ip-7f000001.
+pub(crate) const NONCE_LABEL: &str = "ratls-nonce";
+const IP_LABEL_PREFIX: &str = "ratls-ip-";
+const MAX_LABEL_LEN: usize = 63;
+
+pub(crate) fn encode_server_name(challenge: &[u8], hostname: &str) -> Result<DnsName<'static>, RaTlsError> {
+    let encoded = BASE32_NOPAD.encode(challenge).to_ascii_lowercase();
+    let mut labels = encoded
+        .as_bytes()
+        .chunks(MAX_LABEL_LEN)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>();
+
+    labels.push(NONCE_LABEL.to_owned());
+    labels.push(match hostname.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => format!("{}{}", IP_LABEL_PREFIX, HEXLOWER.encode(&ip.octets())),
+        Ok(IpAddr::V6(ip)) => format!("{}{}", IP_LABEL_PREFIX, HEXLOWER.encode(&ip.octets())),
+        Err(_) => hostname.to_owned()
+    });
+
+    Ok(DnsName::try_from(labels.join("."))?)
+}
+
+// Returns the challenge and the hostname carried in the server name, IP
+// addresses are returned in their usual text form.
+pub(crate) fn decode_server_name(server_name: &str) -> Result<(Vec<u8>, String), RaTlsError> {
+    let marker = format!(".{NONCE_LABEL}.");
+    let (encoded, hostname) = server_name
+        .split_once(marker.as_str())
+        .ok_or(RaTlsError::InvalidServerName)?;
+
+    if hostname.is_empty() || encoded.split('.').any(|label| label.is_empty() || label.len() > MAX_LABEL_LEN) {
+        return Err(RaTlsError::InvalidServerName);
+    }
+
+    let encoded = encoded.replace('.', "").to_ascii_uppercase();
+    let challenge = BASE32_NOPAD
+        .decode(encoded.as_bytes())
+        .map_err(|_| RaTlsError::InvalidServerName)?;
+
+    Ok((challenge, decode_hostname(hostname)?))
+}
+
+fn decode_hostname(hostname: &str) -> Result<String, RaTlsError> {
+    let Some(encoded) = hostname.strip_prefix(IP_LABEL_PREFIX) else {
+        return Ok(hostname.to_owned());
+    };
+    let octets = HEXLOWER
+        .decode(encoded.as_bytes())
+        .map_err(|_| RaTlsError::InvalidServerName)?;
+
+    let ip = match octets.as_slice() {
+        &[a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        octets => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(octets).map_err(|_| RaTlsError::InvalidServerName)?))
+    };
+
+    Ok(ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames_round_trip() {
+        let challenge = [7u8; 64];
+
+        for hostname in ["server.example.com", "127.0.0.1", "::1", "fe80::1:2"] {
+            let server_name = encode_server_name(&challenge, hostname).unwrap();
+            let (decoded_challenge, decoded_hostname) = decode_server_name(server_name.as_ref()).unwrap();
+
+            assert_eq!(decoded_challenge, challenge);
+            assert_eq!(decoded_hostname.parse::<IpAddr>().ok(), hostname.parse::<IpAddr>().ok());
+            if hostname.parse::<IpAddr>().is_err() {
+                assert_eq!(decoded_hostname, hostname);
+            }
+        }
+    }
+
+    #[test]
+    fn ip_addresses_are_single_labels() {
+        let server_name = encode_server_name(&[0; 64], "127.0.0.1").unwrap();
+        assert!(server_name.as_ref().ends_with(".ratls-nonce.ratls-ip-7f000001"));
+    }
+
+    #[test]
+    fn malformed_ip_labels_are_rejected() {
+        let server_name = encode_server_name(&[0; 64], "ratls-ip-7f0000").unwrap();
+        assert!(matches!(decode_server_name(server_name.as_ref()), Err(RaTlsError::InvalidServerName)));
+    }
+}
@@ -1,22 +1,39 @@
 use std::fmt::Debug;
 
-use crate::{error::RaTlsError, tools::read_file};
+use crate::{cmw::CCA_MEDIA_TYPE, error::RaTlsError, tools::read_file};
 
 pub trait InternalTokenResolver: Debug + Send + Sync {
     fn resolve(&self, challenge: &[u8]) -> Result<Vec<u8>, RaTlsError>;
+
+    // Media type of the returned evidence, used to label it in the certificate.
+    fn media_type(&self) -> &str {
+        CCA_MEDIA_TYPE
+    }
 }
 
 #[derive(Debug)]
-pub struct TokenFromFile(Vec<u8>);
+pub struct TokenFromFile {
+    token: Vec<u8>,
+    media_type: String
+}
 
 impl TokenFromFile {
     pub fn from_path(path: impl AsRef<str>) -> Result<Self, RaTlsError> {
-        Ok(Self(read_file(path)?))
+        Ok(Self { token: read_file(path)?, media_type: CCA_MEDIA_TYPE.to_owned() })
+    }
+
+    pub fn with_media_type(mut self, media_type: impl Into<String>) -> Self {
+        self.media_type = media_type.into();
+        self
     }
 }
 
 impl InternalTokenResolver for TokenFromFile {
     fn resolve(&self, _challenge: &[u8]) -> Result<Vec<u8>, RaTlsError> {
-        Ok(self.0.clone())
+        Ok(self.token.clone())
+    }
+
+    fn media_type(&self) -> &str {
+        &self.media_type
     }
 }
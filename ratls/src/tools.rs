@@ -1,5 +1,5 @@
 use std::{fs::File, io::Read};
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
 use rustls::crypto::ring::default_provider;
 use rustls::crypto::CryptoProvider;
 use rustls::{pki_types::{CertificateDer, PrivateKeyDer}, RootCertStore};
@@ -7,32 +7,52 @@ use sha2::{Digest, Sha512};
 
 use crate::error::RaTlsError;
 
+fn read_certificates(reader: &mut dyn BufRead) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(reader).collect()
+}
+
+fn read_private_key(reader: &mut dyn BufRead, source: &str) -> Result<PrivateKeyDer<'static>, RaTlsError> {
+    let private_key = rustls_pemfile::private_key(reader)?;
+    match private_key {
+        None => Err(RaTlsError::PrivateKeyParsingError(format!("No PKCS8-encoded private key found in {source}"))),
+        Some(key) => Ok(key),
+    }
+}
+
 pub(crate) fn load_certificates_from_pem(path: &str) -> std::io::Result<Vec<CertificateDer<'static>>> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
 
-    rustls_pemfile::certs(&mut reader).collect()
+    read_certificates(&mut reader)
 }
 
 pub(crate) fn load_private_key_from_file(path: &str) -> Result<PrivateKeyDer<'static>, RaTlsError> {
     let file = File::open(path)?;
     let mut reader = BufReader::new(file);
 
-    let private_key = rustls_pemfile::private_key(&mut reader)?;
-    match private_key {
-        None => Err(RaTlsError::PrivateKeyParsingError(format!("No PKCS8-encoded private key found in {path}"))),
-        Some(key) => Ok(key),
-    }
+    read_private_key(&mut reader, path)
+}
+
+pub(crate) fn parse_certificates_from_pem(pem: &str) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    read_certificates(&mut pem.as_bytes())
+}
+
+pub(crate) fn parse_private_key_from_pem(pem: &str) -> Result<PrivateKeyDer<'static>, RaTlsError> {
+    read_private_key(&mut pem.as_bytes(), "PEM string")
 }
 
 pub fn load_root_cert_store(path: impl AsRef<str>) -> Result<RootCertStore, RaTlsError> {
-    let der_certs = load_certificates_from_pem(path.as_ref())?;
+    Ok(root_cert_store_from_certificates(load_certificates_from_pem(path.as_ref())?))
+}
+
+
+pub(crate) fn root_cert_store_from_certificates(der_certs: Vec<CertificateDer<'static>>) -> RootCertStore {
     let mut root_store = RootCertStore::empty();
 
     // NOTE: this probably should be interpreted
     let _ = root_store.add_parsable_certificates(der_certs);
 
-    Ok(root_store)
+    root_store
 }
 
 pub(crate) fn hash_realm_challenge(challenge: &[u8], der_public_key: &[u8]) -> Vec<u8> {
@@ -0,0 +1,281 @@
+use std::{io::{Read, Write}, net::TcpStream, sync::{mpsc, Arc}, thread, time::Duration};
+use ratls::{AttestationTransport, EvidenceEncoding, EvidenceVerifiers, RaTlsClientBuilder, RaTlsError, RaTlsServerBuilder,
+            SampleEvidenceResolver, SampleEvidenceVerifier, ServeOptions, ServerHandle, SkipVerification};
+use rustls::{pki_types::ServerName, ClientConnection, StreamOwned};
+
+const SAMPLE_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/vectors/sample-key.pk8");
+
+fn sample_resolver() -> Arc<SampleEvidenceResolver> {
+    Arc::new(SampleEvidenceResolver::from_path(SAMPLE_KEY, vec![0xaa; 32]).unwrap())
+}
+
+fn sample_verifiers(resolver: &SampleEvidenceResolver) -> EvidenceVerifiers {
+    EvidenceVerifiers::new().with_verifier(Arc::new(SampleEvidenceVerifier::new(vec![resolver.public_key().to_vec()])))
+}
+
+fn serve(server: RaTlsServerBuilder) -> ServerHandle {
+    server
+        .build()
+        .unwrap()
+        .serve("127.0.0.1:0", ServeOptions::default(), |_| {}, |_, _| {})
+        .unwrap()
+}
+
+fn attested_client(resolver: &SampleEvidenceResolver) -> RaTlsClientBuilder {
+    RaTlsClientBuilder::new()
+        .with_server_attestation(Arc::new(SkipVerification))
+        .with_evidence_verifiers(sample_verifiers(resolver))
+}
+
+#[test]
+fn attested_server_by_ip_address() {
+    let resolver = sample_resolver();
+
+    for transport in [AttestationTransport::X509Extension, AttestationTransport::PostHandshake] {
+        let handle = serve(RaTlsServerBuilder::new().with_attestation(resolver.clone()).with_attestation_transport(transport));
+        let address = handle.local_addr();
+        let client = attested_client(&resolver).with_attestation_transport(transport).build().unwrap();
+
+        let connection = client.connect(address.to_string(), address.ip().to_string()).unwrap();
+        assert!(connection.peer_attestation().is_some());
+
+        handle.shutdown();
+    }
+}
+
+#[test]
+fn channel_binding_is_equal_on_both_ends() {
+    let resolver = sample_resolver();
+
+    for transport in [AttestationTransport::X509Extension, AttestationTransport::PostHandshake] {
+        for mutual in [false, true] {
+            let mut server = RaTlsServerBuilder::new().with_attestation(resolver.clone()).with_attestation_transport(transport);
+            let mut client = attested_client(&resolver).with_attestation_transport(transport);
+            if mutual {
+                server = server.with_client_attestation(Arc::new(SkipVerification)).with_evidence_verifiers(sample_verifiers(&resolver));
+                client = client.with_attestation(resolver.clone());
+            }
+
+            // The server sends its binding for the client to compare
+            let handle = server.build().unwrap().serve("127.0.0.1:0", ServeOptions::default(), |mut connection| {
+                let binding = connection.channel_binding().unwrap().to_bytes();
+                connection.stream().write_all(&binding).unwrap();
+            }, |_, _| {}).unwrap();
+            let address = handle.local_addr();
+            let mut connection = client.build().unwrap().connect(address.to_string(), address.ip().to_string()).unwrap();
+
+            let binding = connection.channel_binding().unwrap();
+            let mut server_binding = vec![0u8; binding.to_bytes().len()];
+            connection.stream().read_exact(&mut server_binding).unwrap();
+
+            assert_eq!(binding.to_bytes(), server_binding);
+            assert!(binding.server_token_digest().is_some());
+            assert_eq!(binding.client_token_digest().is_some(), mutual);
+
+            handle.shutdown();
+        }
+    }
+}
+
+#[test]
+fn peers_with_different_evidence_encodings() {
+    let resolver = sample_resolver();
+
+    for transport in [AttestationTransport::X509Extension, AttestationTransport::PostHandshake] {
+        let handle = serve(RaTlsServerBuilder::new()
+            .with_attestation(resolver.clone())
+            .with_client_attestation(Arc::new(SkipVerification))
+            .with_evidence_verifiers(sample_verifiers(&resolver))
+            .with_evidence_encoding(EvidenceEncoding::CmwJson)
+            .with_attestation_transport(transport)
+            .with_strict_protocol(true));
+        let address = handle.local_addr();
+        let client = attested_client(&resolver)
+            .with_attestation(resolver.clone())
+            .with_evidence_encoding(EvidenceEncoding::CmwCbor)
+            .with_attestation_transport(transport)
+            .with_strict_protocol(true)
+            .build()
+            .unwrap();
+
+        let connection = client.connect(address.to_string(), address.ip().to_string()).unwrap();
+        assert!(connection.peer_attestation().is_some());
+
+        handle.shutdown();
+    }
+}
+
+#[test]
+fn handlers_do_not_block_handshakes() {
+    let resolver = sample_resolver();
+    let options = ServeOptions::default().with_workers(1).with_read_timeout(None);
+
+    // The handler lives as long as the client keeps the connection open
+    let handle = RaTlsServerBuilder::new()
+        .with_attestation(resolver.clone())
+        .build()
+        .unwrap()
+        .serve("127.0.0.1:0", options, |mut connection| {
+            let _ = connection.stream().read(&mut [0u8; 1]);
+        }, |_, _| {})
+        .unwrap();
+    let address = handle.local_addr();
+    let client = Arc::new(attested_client(&resolver).build().unwrap());
+
+    let first = client.connect(address.to_string(), address.ip().to_string()).unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    let second = client.clone();
+    thread::spawn(move || {
+        let _ = sender.send(second.connect(address.to_string(), address.ip().to_string()).is_ok());
+    });
+    assert!(receiver.recv_timeout(Duration::from_secs(20)).unwrap());
+
+    drop(first);
+    handle.shutdown();
+}
+
+#[test]
+fn connections_above_the_limit_are_refused() {
+    let resolver = sample_resolver();
+    let options = ServeOptions::default().with_max_connections(1).with_read_timeout(None);
+    let (errors, refused) = mpsc::channel();
+    let (handled, done) = mpsc::channel();
+
+    let handle = RaTlsServerBuilder::new()
+        .with_attestation(resolver.clone())
+        .build()
+        .unwrap()
+        .serve("127.0.0.1:0", options, move |mut connection| {
+            let _ = connection.stream().read(&mut [0u8; 1]);
+            let _ = handled.send(());
+        }, move |_, err| {
+            let _ = errors.send(matches!(err, RaTlsError::ServerBusy));
+        })
+        .unwrap();
+    let address = handle.local_addr();
+    let client = attested_client(&resolver).build().unwrap();
+
+    let first = client.connect(address.to_string(), address.ip().to_string()).unwrap();
+    assert!(client.connect(address.to_string(), address.ip().to_string()).is_err());
+    assert!(refused.recv_timeout(Duration::from_secs(5)).unwrap());
+
+    // The slot is free again once the handler returns, it is released right
+    // after the handler signals, so a connection may still be refused
+    drop(first);
+    done.recv_timeout(Duration::from_secs(5)).unwrap();
+    let reconnected = (0..10).any(|_| match client.connect(address.to_string(), address.ip().to_string()) {
+        Ok(_) => true,
+        Err(_) => {
+            assert!(refused.recv_timeout(Duration::from_secs(5)).unwrap());
+            false
+        }
+    });
+    assert!(reconnected);
+
+    handle.shutdown();
+}
+
+#[test]
+fn shutdown_closes_handlers_after_the_drain_timeout() {
+    let resolver = sample_resolver();
+    let options = ServeOptions::default().with_read_timeout(None).with_drain_timeout(Duration::from_millis(200));
+
+    let handle = RaTlsServerBuilder::new()
+        .with_attestation(resolver.clone())
+        .build()
+        .unwrap()
+        .serve("127.0.0.1:0", options, |mut connection| {
+            let _ = connection.stream().read(&mut [0u8; 1]);
+        }, |_, _| {})
+        .unwrap();
+    let address = handle.local_addr();
+    let _connection = attested_client(&resolver).build().unwrap().connect(address.to_string(), address.ip().to_string()).unwrap();
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        handle.shutdown();
+        let _ = sender.send(());
+    });
+    assert!(receiver.recv_timeout(Duration::from_secs(5)).is_ok());
+}
+
+#[test]
+fn handshake_timeout_covers_the_whole_evidence_frame() {
+    let resolver = sample_resolver();
+    let options = ServeOptions::default().with_handshake_timeout(Duration::from_secs(1));
+    let (errors, failed) = mpsc::channel();
+
+    let handle = RaTlsServerBuilder::new()
+        .with_attestation(resolver.clone())
+        .with_attestation_transport(AttestationTransport::PostHandshake)
+        .build()
+        .unwrap()
+        .serve("127.0.0.1:0", options, |_| {}, move |_, _| {
+            let _ = errors.send(());
+        })
+        .unwrap();
+    let address = handle.local_addr();
+
+    let factory = attested_client(&resolver)
+        .with_attestation_transport(AttestationTransport::PostHandshake)
+        .build_config_factory()
+        .unwrap();
+    let (config, _) = factory.make_config().unwrap();
+    let name = ServerName::try_from(address.ip().to_string()).unwrap();
+    let tls = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut stream = StreamOwned::new(tls, TcpStream::connect(address).unwrap());
+
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length).unwrap();
+    stream.read_exact(&mut vec![0u8; u32::from_be_bytes(length) as usize]).unwrap();
+
+    // Every byte arrives well within the timeout, the frame as a whole
+    // doesn't, so the server gives up before the last byte is sent
+    let frame = 16u32.to_be_bytes().into_iter().chain([0u8; 16]).collect::<Vec<_>>();
+    let sent = frame
+        .iter()
+        .position(|byte| stream.write_all(&[*byte]).is_err() || failed.recv_timeout(Duration::from_millis(250)).is_ok());
+    assert!(sent.is_some_and(|sent| sent + 1 < frame.len()));
+
+    handle.shutdown();
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test(flavor = "multi_thread")]
+async fn async_peers_attest_each_other() {
+    use ratls::{RaTlsAcceptor, RaTlsConnector};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+    let resolver = sample_resolver();
+
+    for transport in [AttestationTransport::X509Extension, AttestationTransport::PostHandshake] {
+        let server = RaTlsServerBuilder::new()
+            .with_attestation(resolver.clone())
+            .with_attestation_transport(transport)
+            .with_client_attestation(Arc::new(SkipVerification))
+            .with_evidence_verifiers(sample_verifiers(&resolver));
+        let acceptor = RaTlsAcceptor::from_config_factory(server.build_config_factory().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let accepted = tokio::spawn(async move {
+            let (sock, peer) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept_from_peer(sock, peer.ip()).await.unwrap();
+            assert!(stream.peer_attestation().is_some());
+            stream.write_all(b"ping").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let client = attested_client(&resolver).with_attestation(resolver.clone()).with_attestation_transport(transport);
+        let connector = RaTlsConnector::from_config_factory(client.build_config_factory().unwrap());
+        let sock = TcpStream::connect(address).await.unwrap();
+        let mut stream = connector.connect(address.ip().to_string(), sock).await.unwrap();
+        assert!(stream.peer_attestation().is_some());
+
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        accepted.await.unwrap();
+    }
+}
@@ -1,9 +1,9 @@
 use log::error;
-use ratls::{InternalTokenResolver, RaTlsCertResolver, TokenFromFile, load_root_cert_store};
+use ratls::{InternalTokenResolver, RaTlsClientBuilder, TokenFromFile};
 use rustls::ClientConfig;
 use std::sync::Arc;
 
-use crate::{GenericResult, token, utils};
+use crate::{GenericResult, token};
 
 #[derive(clap::ValueEnum, Default, Debug, Clone)]
 pub enum Protocol
@@ -22,25 +22,26 @@ pub struct Config
     pub token: Option<String>,
 }
 
-pub(crate) fn tls_client_config(config: Config) -> GenericResult<ClientConfig>
+// Lets servers that don't know the ratls protocol pick plain HTTP
+fn with_http_alpn(mut tls_config: ClientConfig) -> ClientConfig
 {
-    utils::install_default_crypto_provider()?;
+    tls_config.alpn_protocols.push(b"http/1.1".to_vec());
+    tls_config
+}
 
-    let root_cert_store = load_root_cert_store(&config.root_ca)
-        .inspect_err(|_| error!("Failed to load root-ca: {}", config.root_ca))?;
-    let tls_config = ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_no_client_auth();
+pub(crate) fn tls_client_config(config: Config) -> GenericResult<ClientConfig>
+{
+    let (tls_config, _) = RaTlsClientBuilder::new()
+        .with_root_ca_file(&config.root_ca)
+        .inspect_err(|_| error!("Failed to load root-ca: {}", config.root_ca))?
+        .build_config_factory()?
+        .make_config()?;
 
-    Ok(tls_config)
+    Ok(with_http_alpn(tls_config))
 }
 
 pub(crate) fn ratls_client_config(config: Config) -> GenericResult<ClientConfig>
 {
-    utils::install_default_crypto_provider()?;
-
-    let root_cert_store = load_root_cert_store(&config.root_ca)
-        .inspect_err(|_| error!("Failed to load root-ca: {}", config.root_ca))?;
     let token_resolver: Arc<dyn InternalTokenResolver> = match config.token {
         Some(path) => Arc::new(
             TokenFromFile::from_path(&path)
@@ -48,11 +49,12 @@ pub(crate) fn ratls_client_config(config: Config) -> GenericResult<ClientConfig>
         ),
         None => Arc::new(token::IoctlTokenResolver()),
     };
-    let resolver = Arc::new(RaTlsCertResolver::from_token_resolver(token_resolver)?);
-
-    let tls_config = ClientConfig::builder()
-        .with_root_certificates(root_cert_store)
-        .with_client_cert_resolver(resolver);
-
-    Ok(tls_config)
+    let (tls_config, _) = RaTlsClientBuilder::new()
+        .with_root_ca_file(&config.root_ca)
+        .inspect_err(|_| error!("Failed to load root-ca: {}", config.root_ca))?
+        .with_attestation(token_resolver)
+        .build_config_factory()?
+        .make_config()?;
+
+    Ok(with_http_alpn(tls_config))
 }
@@ -1,7 +1,6 @@
 mod files;
 mod httpd;
 mod tls;
-mod utils;
 
 pub type GenericResult<T> = Result<T, Box<dyn std::error::Error>>;
 
@@ -3,7 +3,7 @@ use futures_util::pin_mut;
 use hyper::body::Incoming;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use log::{debug, error, warn};
-use ratls::{ChainVerifier, RaTlsCertVeryfier};
+use ratls::{ChainVerifier, RaTlsServerBuilder, ServerConfigFactory};
 #[cfg(not(feature = "disable-realm-verifier"))]
 use realm_verifier::{RealmVerifier, parser_json::parse_value};
 use std::sync::Arc;
@@ -11,12 +11,11 @@ use std::sync::Arc;
 use std::{fs::File, io::BufReader};
 use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
-use tokio_rustls::rustls::ServerConfig;
 use tower_service::Service;
 #[cfg(not(feature = "disable-veraison"))]
 use veraison_verifier::VeraisonTokenVerifer;
 
-use crate::{GenericResult, utils};
+use crate::GenericResult;
 
 #[derive(clap::ValueEnum, Default, Debug, Clone)]
 pub enum Protocol
@@ -39,24 +38,17 @@ pub struct Config
     pub reference_json: String,
 }
 
-fn tls_server_config(config: Config) -> GenericResult<Arc<ServerConfig>>
+fn tls_server_config(config: Config) -> GenericResult<ServerConfigFactory>
 {
-    utils::install_default_crypto_provider()?;
+    let config_factory = RaTlsServerBuilder::new()
+        .with_certificate_files(&config.cert, &config.key)?
+        .build_config_factory()?;
 
-    let tls_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(
-            utils::load_certificates_from_pem(&config.cert)?,
-            utils::load_private_key_from_file(&config.key)?,
-        )?;
-
-    Ok(Arc::new(tls_config))
+    Ok(config_factory)
 }
 
-fn ratls_server_config(config: Config) -> GenericResult<Arc<ServerConfig>>
+fn ratls_server_config(config: Config) -> GenericResult<ServerConfigFactory>
 {
-    utils::install_default_crypto_provider()?;
-
     #[cfg(not(feature = "disable-realm-verifier"))]
     let reference_measurements = {
         let json_reader = BufReader::new(File::open(&config.reference_json)?);
@@ -75,16 +67,12 @@ fn ratls_server_config(config: Config) -> GenericResult<Arc<ServerConfig>>
         Arc::new(RealmVerifier::init(reference_measurements.clone())),
     ]));
 
-    let tls_config = ServerConfig::builder()
-        .with_client_cert_verifier(Arc::new(RaTlsCertVeryfier::from_token_verifier(
-            client_token_verifier,
-        )))
-        .with_single_cert(
-            utils::load_certificates_from_pem(&config.cert)?,
-            utils::load_private_key_from_file(&config.key)?,
-        )?;
+    let config_factory = RaTlsServerBuilder::new()
+        .with_certificate_files(&config.cert, &config.key)?
+        .with_client_attestation(client_token_verifier)
+        .build_config_factory()?;
 
-    Ok(Arc::new(tls_config))
+    Ok(config_factory)
 }
 
 pub(crate) async fn serve_tls(
@@ -95,8 +83,8 @@ pub(crate) async fn serve_tls(
 {
     debug!("Initializing TLS");
 
-    let tls_config = tls_server_config(config)?;
-    serve_internal(listener, app, tls_config).await
+    let config_factory = tls_server_config(config)?;
+    serve_internal(listener, app, config_factory).await
 }
 
 pub(crate) async fn serve_ratls(
@@ -107,8 +95,8 @@ pub(crate) async fn serve_ratls(
 {
     debug!("Initializing RA-TLS");
 
-    let tls_config = ratls_server_config(config)?;
-    serve_internal(listener, app, tls_config).await
+    let config_factory = ratls_server_config(config)?;
+    serve_internal(listener, app, config_factory).await
 }
 
 // For details on the code see here:
@@ -116,7 +104,7 @@ pub(crate) async fn serve_ratls(
 async fn serve_internal(
     listener: TcpListener,
     app: Router,
-    tls_config: Arc<ServerConfig>,
+    config_factory: ServerConfigFactory,
 ) -> GenericResult<()>
 {
     pin_mut!(listener);
@@ -125,7 +113,10 @@ async fn serve_internal(
         let tower_service = app.clone();
 
         let (cnx, addr) = listener.accept().await?;
-        let tls_acceptor = TlsAcceptor::from(tls_config.clone());
+        let (mut tls_config, _) = config_factory.make_config_for_peer(addr.ip());
+        // Plain HTTPS clients don't know the ratls protocol
+        tls_config.alpn_protocols.push(b"http/1.1".to_vec());
+        let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
 
         tokio::spawn(async move {
             let Ok(stream) = tls_acceptor.accept(cnx).await else {